@@ -0,0 +1,403 @@
+use ffmpeg_next as ffmpeg;
+use video_rotator::{
+    Deinterlace, EncoderOptions, JobInfo, Method, Operation, Pipeline, Progress, ProgressSink,
+    Quality, Rotate, SUPPORTED_EXTENSIONS, Scale, Segment, StreamInfo, Throttle, Tilt,
+    TrackOptions, parse_size,
+};
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
+};
+
+const USAGE: &str = "\
+Usage: video-rotator-cli [OPTIONS] --output <DIR> <FILES>...
+
+Options:
+  -o, --output <DIR>       Directory where the rotated videos are saved
+  -r, --rotate <ROTATION>  Clockwise rotation: 0, 90, 180, 270 or upright, or mirroring: hflip,
+                           vflip, transpose (90 and hflip) or transverse (270 and hflip) [default: 0]
+  -t, --tilt <DEGREES>     Additional clockwise rotation by any angle, e.g. -3.5
+      --fill <FILL>        Corners uncovered by the tilt: crop, blur or a color like black or
+                           #rrggbb [default: crop]
+  -a, --aspect <RATIO>     Crop or pad the rotated video to the aspect ratio, e.g. 9:16, 4:5:blur,
+                           9:16:#ffffff or 9:16+0+200 for the offset of the crop
+  -x, --operation <OP>     Apply after the rotation, the tilt and the aspect ratio, in the given
+                           order: crop=WxH[+X+Y], scale=WxH, pad=WxH[+X+Y][:COLOR],
+                           aspect=W:H[:FILL][+X+Y], trim=START-END, fps=FPS, rotate=ROTATION or
+                           tilt=DEGREES[:FILL]
+      --fps <FPS>          Convert variable frame rate recordings to a constant one, e.g. 30 or
+                           30000/1001 [default: keep the timestamps of frames]
+      --scale <SCALE>      Scale the result: WxH exactly, fit:WxH into the box keeping the aspect
+                           ratio (fit:1920x or fit:x1080 limit one side), 1080p for the shorter
+                           side or 50%
+  -s, --segment <SEGMENT>  Rotate a time range differently, e.g. 1:05-2:30=90 or 2:30-=270 until
+                           the end. Can be given several times
+      --canvas <WxH>       Output frame size with segments, frames are fitted into it with black
+                           bars [default: the size outside of segments]
+      --deinterlace <MODE> Deinterlace before rotating: auto for streams marked interlaced,
+                           detect to analyze unmarked ones too, always or never [default: auto]
+      --video-streams <INDICES>
+                           Rotate only these video streams, e.g. 0,2, and copy the rest
+                           [default: all video streams]
+      --rotate-cover-art   Rotate the attached cover art too [default: copy it as it is]
+      --drop <INDICES>     Leave these streams out of the output, e.g. 2,3 for extra audio tracks
+  -m, --method <METHOD>    How to rotate: reencode or metadata [default: reencode]
+  -c, --codec <CODEC>      Output video codec: h264, h265, mpeg4, vp8, vp9 or ffv1 [default: h264]
+      --preset <PRESET>    Encoder preset, e.g. ultrafast, medium or veryslow
+      --crf <CRF>          Constant rate factor, lower is better
+      --bitrate <KBPS>     Average bitrate in kbit/s instead of the CRF
+      --match-source       Choose the bitrate or CRF close to the input video
+      --two-pass           Encode twice to distribute the bitrate better (H.264 only)
+      --lossless           Encode losslessly and check every frame against the input
+      --no-verify          Don't check lossless outputs frame by frame
+      --profile <PROFILE>  Codec profile, e.g. high or main
+      --level <LEVEL>      Codec level, e.g. 4.1
+      --max-compatibility  Encode 8-bit 4:2:0 video every player decodes instead of the pixel
+                           format closest to the input
+  -h, --help               Print this help
+";
+
+fn main() -> ExitCode {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            print!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(err) => {
+            eprintln!("error: {err:#}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    if let Err(err) = video_rotator::init() {
+        eprintln!("error: cannot initialize FFmpeg: {err:#}");
+        return ExitCode::FAILURE;
+    }
+    // The pipeline dumps a lot of details about the streams, which only clutters the progress.
+    ffmpeg::log::set_level(ffmpeg::log::Level::Error);
+
+    let mut failed = 0;
+    for input in &args.inputs {
+        if let Err(err) = rotate_file(input, &args) {
+            eprintln!("\n{}: {err:#}", input.display());
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        eprintln!("{failed} of {} videos failed", args.inputs.len());
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+fn rotate_file(input: &Path, args: &Args) -> anyhow::Result<()> {
+    if !video_rotator::is_supported(input) {
+        anyhow::bail!(
+            "Unsupported file extension, expected one of: {}",
+            SUPPORTED_EXTENSIONS.join(", ")
+        );
+    }
+
+    let output = video_rotator::generate_unique_filename(&args.output_dir, input)?;
+
+    let mut builder = Pipeline::builder()
+        .input(input)
+        .output(&output)
+        .rotate(args.rotate)
+        .deinterlace(args.deinterlace)
+        .rotate_attached_pictures(args.rotate_cover_art)
+        .method(args.method)
+        .encoder_options(args.encoder_options.clone())
+        .skip_verification(args.skip_verification);
+    if let Some(tilt) = args.tilt {
+        builder = builder.tilt(tilt);
+    }
+    for operation in &args.operations {
+        builder = builder.operation(*operation);
+    }
+    if let Some(scale) = args.scale {
+        builder = builder.scale(scale);
+    }
+    if let Some(video_streams) = &args.video_streams {
+        builder = builder.video_streams(video_streams.clone());
+    }
+    for &index in &args.dropped_streams {
+        builder = builder.track(index, TrackOptions::dropped());
+    }
+    for segment in &args.segments {
+        builder = builder.segment(*segment);
+    }
+    if let Some((width, height)) = args.canvas {
+        builder = builder.canvas(width, height);
+    }
+
+    let mut pipeline = builder.build()?;
+    pipeline.run(Throttle::new(
+        TerminalProgress {
+            name: input.display().to_string(),
+        },
+        Duration::from_millis(250),
+    ))
+}
+
+/// Prints the progress of a file into a single line of stderr.
+struct TerminalProgress {
+    name: String,
+}
+
+impl ProgressSink for TerminalProgress {
+    fn started(&mut self, job: &JobInfo) {
+        eprintln!("{} -> {}", job.input.display(), job.output.display());
+    }
+
+    fn stream_info(&mut self, stream: &StreamInfo) {
+        if stream.dropped {
+            eprintln!("  stream #{}: {} dropped", stream.index, stream.codec);
+        } else if let Some(quality) = stream.quality {
+            eprintln!("  stream #{}: {} -> {quality}", stream.index, stream.codec);
+        }
+    }
+
+    fn progress(&mut self, progress: &Progress) {
+        let eta = progress
+            .eta
+            .map(|eta| format_duration(&eta))
+            .unwrap_or_else(|| String::from("--:--"));
+
+        // The terminal may be gone, there's nobody to report it to anyway.
+        let _ = write!(
+            std::io::stderr(),
+            "\r{}: {:>3.0}% {:>6.1} fps {:>8.1} MiB ETA {eta}  ",
+            self.name,
+            progress.fraction * 100.0,
+            progress.fps,
+            progress.bytes_written as f64 / (1024.0 * 1024.0),
+        );
+    }
+
+    fn finished(&mut self, progress: &Progress) {
+        eprintln!(
+            "\r{}: done in {}, {:.1} MiB{:>30}",
+            self.name,
+            format_duration(&progress.elapsed),
+            progress.bytes_written as f64 / (1024.0 * 1024.0),
+            "",
+        );
+    }
+}
+
+fn format_duration(duration: &Duration) -> String {
+    let seconds = duration.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+struct Args {
+    inputs: Vec<PathBuf>,
+    output_dir: PathBuf,
+    rotate: Rotate,
+    tilt: Option<Tilt>,
+    operations: Vec<Operation>,
+    scale: Option<Scale>,
+    segments: Vec<Segment>,
+    canvas: Option<(u32, u32)>,
+    deinterlace: Deinterlace,
+    video_streams: Option<Vec<usize>>,
+    rotate_cover_art: bool,
+    dropped_streams: Vec<usize>,
+    method: Method,
+    encoder_options: EncoderOptions,
+    skip_verification: bool,
+}
+
+impl Args {
+    /// Returns `None` when the help is requested.
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> anyhow::Result<Option<Self>> {
+        let mut inputs = vec![];
+        let mut output_dir = None;
+        let mut rotation = String::from("0");
+        let mut method = String::from("reencode");
+        let mut tilt = None;
+        let mut fill = String::from("crop");
+        let mut aspect = None;
+        let mut operations = vec![];
+        let mut fps = None;
+        let mut scale = None;
+        let mut segments = vec![];
+        let mut canvas = None;
+        let mut deinterlace = Deinterlace::Auto;
+        let mut video_streams = None;
+        let mut rotate_cover_art = false;
+        let mut dropped_streams = vec![];
+        let mut codec = String::from("h264");
+        let mut preset = None;
+        let mut crf = None;
+        let mut bitrate = None;
+        let mut profile = None;
+        let mut level = None;
+        let mut match_source = false;
+        let mut two_pass = false;
+        let mut max_compatibility = false;
+        let mut lossless = false;
+        let mut skip_verification = false;
+
+        while let Some(arg) = args.next() {
+            let mut value_of = |option: &str| {
+                args.next()
+                    .ok_or(anyhow::anyhow!("Missing value for {option}"))
+            };
+
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "-o" | "--output" => output_dir = Some(PathBuf::from(value_of(&arg)?)),
+                "-r" | "--rotate" => rotation = value_of(&arg)?,
+                "-t" | "--tilt" => tilt = Some(value_of(&arg)?),
+                "--fill" => fill = value_of(&arg)?,
+                "-a" | "--aspect" => aspect = Some(value_of(&arg)?),
+                "-x" | "--operation" => operations.push(value_of(&arg)?.parse()?),
+                "--fps" => fps = Some(value_of(&arg)?),
+                "--scale" => scale = Some(value_of(&arg)?.parse()?),
+                "-s" | "--segment" => segments.push(value_of(&arg)?.parse()?),
+                "--canvas" => canvas = Some(value_of(&arg)?),
+                "--deinterlace" => deinterlace = value_of(&arg)?.parse()?,
+                "--video-streams" => video_streams = Some(value_of(&arg)?),
+                "--rotate-cover-art" => rotate_cover_art = true,
+                "--drop" => dropped_streams = parse_indices(&value_of(&arg)?)?,
+                "-m" | "--method" => method = value_of(&arg)?,
+                "-c" | "--codec" => codec = value_of(&arg)?,
+                "--preset" => preset = Some(value_of(&arg)?),
+                "--crf" => crf = Some(value_of(&arg)?),
+                "--bitrate" => bitrate = Some(value_of(&arg)?),
+                "--profile" => profile = Some(value_of(&arg)?),
+                "--level" => level = Some(value_of(&arg)?),
+                "--match-source" => match_source = true,
+                "--two-pass" => two_pass = true,
+                "--max-compatibility" => max_compatibility = true,
+                "--lossless" => lossless = true,
+                "--no-verify" => skip_verification = true,
+                option if option.starts_with('-') => anyhow::bail!("Unknown option {option}"),
+                _ => inputs.push(PathBuf::from(&arg)),
+            }
+        }
+
+        let output_dir = output_dir.ok_or(anyhow::anyhow!("The output directory is required"))?;
+        if !output_dir.is_dir() {
+            anyhow::bail!("{} is not a directory", output_dir.display());
+        }
+
+        if inputs.is_empty() {
+            anyhow::bail!("No input files are given");
+        }
+
+        let (rotate, method) = match (rotation.as_str(), method.as_str()) {
+            ("upright", _) => (Rotate::Deg0, Method::BakeIn),
+            (rotation, method) => (rotation.parse()?, method.parse()?),
+        };
+
+        let tilt = match tilt {
+            Some(degrees) => Some(Tilt {
+                degrees: degrees
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid tilt {degrees}"))?,
+                fill: fill.parse()?,
+            }),
+            None => None,
+        };
+
+        if let Some(aspect) = aspect {
+            operations.insert(0, format!("aspect={aspect}").parse()?);
+        }
+        if let Some(fps) = fps {
+            operations.push(format!("fps={fps}").parse()?);
+        }
+
+        let video_streams = match video_streams {
+            Some(indices) => Some(parse_indices(&indices)?),
+            None => None,
+        };
+
+        let canvas = match canvas {
+            Some(canvas) => Some(parse_size(&canvas)?),
+            None => None,
+        };
+
+        let mut encoder_options = EncoderOptions::new(codec.parse()?);
+        if preset.is_some() {
+            encoder_options.preset = preset;
+        }
+        encoder_options.profile = profile;
+        encoder_options.level = level;
+        encoder_options.two_pass = two_pass;
+        encoder_options.max_compatibility = max_compatibility;
+        match (crf, bitrate) {
+            _ if (match_source || lossless) && (crf.is_some() || bitrate.is_some()) => {
+                anyhow::bail!(
+                    "--match-source and --lossless can't be combined with --crf or --bitrate"
+                )
+            }
+            _ if match_source && lossless => {
+                anyhow::bail!("Only one of --match-source and --lossless can be given")
+            }
+            _ if match_source => encoder_options.quality = Quality::MatchSource,
+            _ if lossless => encoder_options.quality = Quality::Lossless,
+            (Some(_), Some(_)) => anyhow::bail!("Only one of --crf and --bitrate can be given"),
+            (Some(crf), None) => {
+                encoder_options.quality = Quality::Crf(
+                    crf.parse()
+                        .map_err(|_| anyhow::anyhow!("Invalid CRF {crf}"))?,
+                );
+            }
+            (None, Some(bitrate)) => {
+                let kbps: usize = bitrate
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid bitrate {bitrate}"))?;
+                encoder_options.quality = Quality::Bitrate(kbps * 1000);
+            }
+            (None, None) => {}
+        }
+        if method != Method::Metadata {
+            encoder_options.validate()?;
+        }
+
+        Ok(Some(Self {
+            inputs,
+            output_dir,
+            rotate,
+            tilt,
+            operations,
+            scale,
+            segments,
+            canvas,
+            deinterlace,
+            video_streams,
+            rotate_cover_art,
+            dropped_streams,
+            method,
+            encoder_options,
+            skip_verification,
+        }))
+    }
+}
+
+/// Parses stream indices separated by commas, e.g. `0,2`.
+fn parse_indices(indices: &str) -> anyhow::Result<Vec<usize>> {
+    indices
+        .split(',')
+        .map(|index| {
+            index
+                .trim()
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid stream index {index}"))
+        })
+        .collect()
+}
@@ -0,0 +1,42 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+/// Handle to stop a running [`Pipeline`](crate::Pipeline) from another thread.
+///
+/// The pipeline checks it before every packet, so it stops soon after [`CancelHandle::cancel`]
+/// and returns [`Cancelled`].
+#[derive(Clone, Default, Debug)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    /// Makes a handle which isn't cancelled yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks the pipeline to stop. All clones of the handle are cancelled together.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether [`CancelHandle::cancel`] was called on this handle or any of its clones.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The error of a pipeline stopped by [`CancelHandle::cancel`].
+///
+/// Check it with `error.is::<Cancelled>()` to tell cancellation from failures.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The rotation is cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
@@ -0,0 +1,955 @@
+use ffmpeg_next as ffmpeg;
+
+use std::{fmt, path::Path, str::FromStr};
+
+use crate::{
+    filter::Filter,
+    source::{HdrMetadata, VideoProperties},
+};
+
+/// Codecs video streams can be re-encoded with. Not every FFmpeg build has encoders for all of
+/// them, see [`VideoCodec::available`].
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum VideoCodec {
+    /// H.264 (AVC) with libx264.
+    #[default]
+    H264,
+    /// H.265 (HEVC) with libx265.
+    H265,
+    /// MPEG-4 Part 2 with the FFmpeg's own encoder.
+    Mpeg4,
+    /// VP8 with libvpx.
+    Vp8,
+    /// VP9 with libvpx.
+    Vp9,
+    /// Lossless codec, so it ignores the quality.
+    Ffv1,
+}
+
+impl VideoCodec {
+    /// All codecs, whether the linked FFmpeg has their encoders or not.
+    pub const ALL: [VideoCodec; 6] = [
+        VideoCodec::H264,
+        VideoCodec::H265,
+        VideoCodec::Mpeg4,
+        VideoCodec::Vp8,
+        VideoCodec::Vp9,
+        VideoCodec::Ffv1,
+    ];
+
+    /// Returns codecs having encoders in the linked FFmpeg.
+    pub fn available() -> Vec<VideoCodec> {
+        Self::ALL
+            .into_iter()
+            .filter(|codec| codec.encoder().is_some())
+            .collect()
+    }
+
+    /// The human readable name, e.g. `H.264`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            VideoCodec::H264 => "H.264",
+            VideoCodec::H265 => "H.265",
+            VideoCodec::Mpeg4 => "MPEG-4",
+            VideoCodec::Vp8 => "VP8",
+            VideoCodec::Vp9 => "VP9",
+            VideoCodec::Ffv1 => "FFV1",
+        }
+    }
+
+    pub(crate) fn encoder(&self) -> Option<ffmpeg::Codec> {
+        let encoder_name = match self {
+            VideoCodec::H264 => "libx264",
+            VideoCodec::H265 => "libx265",
+            VideoCodec::Mpeg4 => "mpeg4",
+            VideoCodec::Vp8 => "libvpx",
+            VideoCodec::Vp9 => "libvpx-vp9",
+            VideoCodec::Ffv1 => "ffv1",
+        };
+
+        ffmpeg::encoder::find_by_name(encoder_name)
+    }
+
+    /// Whether the encoder has presets like `medium` or `veryslow`.
+    pub fn supports_presets(&self) -> bool {
+        matches!(self, VideoCodec::H264 | VideoCodec::H265)
+    }
+
+    /// Whether the encoder has profiles and levels.
+    pub fn supports_profiles(&self) -> bool {
+        matches!(self, VideoCodec::H264 | VideoCodec::H265)
+    }
+
+    /// Returns the worst constant rate factor or `None` if the encoder doesn't have it.
+    pub fn max_crf(&self) -> Option<u8> {
+        match self {
+            VideoCodec::H264 | VideoCodec::H265 => Some(51),
+            VideoCodec::Vp8 | VideoCodec::Vp9 => Some(63),
+            VideoCodec::Mpeg4 | VideoCodec::Ffv1 => None,
+        }
+    }
+
+    /// Returns the constant rate factor giving a good quality for its size.
+    pub fn default_crf(&self) -> Option<u8> {
+        match self {
+            VideoCodec::H264 => Some(23),
+            VideoCodec::H265 => Some(28),
+            VideoCodec::Vp8 | VideoCodec::Vp9 => Some(31),
+            VideoCodec::Mpeg4 | VideoCodec::Ffv1 => None,
+        }
+    }
+
+    /// Whether the codec is always lossless.
+    pub fn is_lossless(&self) -> bool {
+        matches!(self, VideoCodec::Ffv1)
+    }
+
+    /// Whether the encoder can be switched into the lossless mode by [`Quality::Lossless`].
+    pub fn supports_lossless(&self) -> bool {
+        matches!(
+            self,
+            VideoCodec::H264 | VideoCodec::H265 | VideoCodec::Vp9 | VideoCodec::Ffv1
+        )
+    }
+
+    /// Whether the encoder can make two passes over the video to hit the bitrate precisely.
+    pub fn supports_two_pass(&self) -> bool {
+        matches!(self, VideoCodec::H264)
+    }
+
+    /// Returns the largest denominator of time bases the codec can store, if it's limited.
+    /// MPEG-4 Part 2 counts time in 16 bits, while phones record in 1/90000 s.
+    pub(crate) fn max_time_base_denominator(&self) -> Option<i32> {
+        match self {
+            VideoCodec::Mpeg4 => Some(65535),
+            _ => None,
+        }
+    }
+
+    /// Returns how many bits the codec spends relative to H.264 for the same quality.
+    fn relative_bit_rate(&self) -> f64 {
+        match self {
+            VideoCodec::H264 | VideoCodec::Vp8 => 1.0,
+            VideoCodec::H265 | VideoCodec::Vp9 => 0.6,
+            VideoCodec::Mpeg4 => 1.5,
+            VideoCodec::Ffv1 => f64::INFINITY,
+        }
+    }
+}
+
+/// Parses `h264`, `h265` or `hevc`, `mpeg4`, `vp8`, `vp9` or `ffv1`.
+impl FromStr for VideoCodec {
+    type Err = anyhow::Error;
+
+    fn from_str(codec: &str) -> Result<Self, Self::Err> {
+        match codec {
+            "h264" => Ok(VideoCodec::H264),
+            "h265" | "hevc" => Ok(VideoCodec::H265),
+            "mpeg4" => Ok(VideoCodec::Mpeg4),
+            "vp8" => Ok(VideoCodec::Vp8),
+            "vp9" => Ok(VideoCodec::Vp9),
+            "ffv1" => Ok(VideoCodec::Ffv1),
+            _ => anyhow::bail!("Unknown codec {codec}"),
+        }
+    }
+}
+
+/// Returns how many bits the input codec spends relative to H.264 for the same quality, or `None`
+/// for intra-frame and lossless codecs, whose bitrate says nothing about the quality of inter-frame
+/// ones.
+fn source_relative_bit_rate(codec: ffmpeg::codec::Id) -> Option<f64> {
+    use ffmpeg::codec::Id;
+
+    match codec {
+        Id::MPEG1VIDEO | Id::MPEG2VIDEO => Some(2.0),
+        Id::MPEG4 | Id::H263 | Id::MSMPEG4V3 | Id::THEORA => Some(1.5),
+        Id::H264 | Id::VP8 | Id::VC1 => Some(1.0),
+        Id::HEVC | Id::VP9 => Some(0.6),
+        Id::AV1 => Some(0.5),
+        _ => None,
+    }
+}
+
+/// Rate control of the encoder.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Quality {
+    /// Constant rate factor, where 0 is the best quality.
+    Crf(u8),
+    /// Average bitrate in bits per second.
+    Bitrate(usize),
+    /// Picks the bitrate or CRF for every stream, so the output stays close to the input in size
+    /// and quality.
+    MatchSource,
+    /// Mathematically lossless encoding, e.g. `qp=0` of x264.
+    Lossless,
+}
+
+impl fmt::Display for Quality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Quality::Crf(crf) => write!(f, "CRF {crf}"),
+            Quality::Bitrate(bit_rate) => write!(f, "{:.1} Mbit/s", *bit_rate as f64 / 1_000_000.0),
+            Quality::MatchSource => write!(f, "matching the source"),
+            Quality::Lossless => write!(f, "lossless"),
+        }
+    }
+}
+
+/// Which pass of the encoding is running.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Pass {
+    Single,
+    /// Only collects statistics of the video for the second pass.
+    First,
+    Second,
+}
+
+/// Options of the encoder used for re-encoded video streams.
+#[derive(Clone, Debug)]
+pub struct EncoderOptions {
+    /// The codec video streams are encoded with.
+    pub codec: VideoCodec,
+    /// The encoder preset, e.g. `ultrafast`, `medium` or `veryslow`.
+    pub preset: Option<String>,
+    /// The rate control of the encoder.
+    pub quality: Quality,
+    /// The codec profile, e.g. `high` or `main`.
+    pub profile: Option<String>,
+    /// The codec level, e.g. `4.1`.
+    pub level: Option<String>,
+    /// Encodes the video twice to distribute the bitrate better. Only for streams with the
+    /// bitrate, whether it's given or matches the source.
+    pub two_pass: bool,
+    /// Converts frames to 8-bit 4:2:0 (`yuv420p`), which every player and device decodes,
+    /// instead of the pixel format closest to the source the encoder and profile support.
+    /// Lossless encoding fails rather than dropping the depth or chroma of the source.
+    pub max_compatibility: bool,
+}
+
+impl Default for EncoderOptions {
+    fn default() -> Self {
+        Self::new(VideoCodec::H264)
+    }
+}
+
+impl EncoderOptions {
+    /// Returns the default options of the codec: the `medium` preset if it has presets and the
+    /// default CRF, or 8 Mbit/s for codecs without CRF.
+    pub fn new(codec: VideoCodec) -> Self {
+        Self {
+            codec,
+            preset: codec.supports_presets().then(|| String::from("medium")),
+            quality: codec
+                .default_crf()
+                .map_or(Quality::Bitrate(8_000_000), Quality::Crf),
+            profile: None,
+            level: None,
+            two_pass: false,
+            max_compatibility: false,
+        }
+    }
+
+    /// Checks that the codec is available and supports the given options.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let codec = self.codec;
+
+        if codec.encoder().is_none() {
+            anyhow::bail!(
+                "The {} encoder is not available in the linked FFmpeg",
+                codec.name()
+            );
+        }
+
+        if self.preset.is_some() && !codec.supports_presets() {
+            anyhow::bail!("{} doesn't support presets", codec.name());
+        }
+
+        if (self.profile.is_some() || self.level.is_some()) && !codec.supports_profiles() {
+            anyhow::bail!("{} doesn't support profiles and levels", codec.name());
+        }
+
+        if self.two_pass && !codec.supports_two_pass() {
+            anyhow::bail!("{} doesn't support two-pass encoding", codec.name());
+        }
+
+        if self.quality == Quality::Lossless && !codec.supports_lossless() {
+            anyhow::bail!("{} doesn't have the lossless mode", codec.name());
+        }
+
+        if self.two_pass && matches!(self.quality, Quality::Crf(_) | Quality::Lossless) {
+            anyhow::bail!("Two-pass encoding needs the bitrate");
+        }
+
+        if let (Quality::Crf(crf), false) = (self.quality, codec.is_lossless()) {
+            match codec.max_crf() {
+                Some(max_crf) if crf > max_crf => {
+                    anyhow::bail!("The CRF of {} must be from 0 to {max_crf}", codec.name())
+                }
+                Some(_) => {}
+                None => anyhow::bail!("{} supports only the bitrate", codec.name()),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the pixel format frames of the given one are converted into before encoding. It's
+    /// the one losing the least of them among formats the encoder and the profile support. Fails
+    /// if the encoding is lossless, but the format loses anything.
+    pub(crate) fn pixel_format(
+        &self,
+        input: ffmpeg::format::Pixel,
+    ) -> anyhow::Result<ffmpeg::format::Pixel> {
+        use ffmpeg::format::Pixel;
+
+        let encoder = self.codec.encoder().ok_or(anyhow::anyhow!(
+            "The {} encoder is not available in the linked FFmpeg",
+            self.codec.name()
+        ))?;
+        let formats: Vec<Pixel> = match encoder.video()?.formats() {
+            Some(formats) => formats.collect(),
+            // Encoders not listing their formats take any.
+            None if self.max_compatibility => vec![Pixel::YUV420P],
+            None => vec![input],
+        };
+
+        let mut candidates: Vec<ffmpeg::ffi::AVPixelFormat> = formats
+            .into_iter()
+            .filter(|format| !self.max_compatibility || *format == Pixel::YUV420P)
+            .filter(|format| self.profile_supports(*format))
+            .map(Into::into)
+            .collect();
+        if candidates.is_empty() {
+            anyhow::bail!(
+                "{} doesn't support {} with the {} profile",
+                self.codec.name(),
+                if self.max_compatibility {
+                    "8-bit 4:2:0"
+                } else {
+                    "any pixel format"
+                },
+                self.profile.as_deref().unwrap_or("default"),
+            );
+        }
+        candidates.push(ffmpeg::ffi::AVPixelFormat::AV_PIX_FMT_NONE);
+
+        let mut loss = 0;
+        let best: Pixel = unsafe {
+            ffmpeg::ffi::avcodec_find_best_pix_fmt_of_list(
+                candidates.as_ptr(),
+                input.into(),
+                0,
+                &mut loss,
+            )
+        }
+        .into();
+
+        if self.is_lossless() && loss & PIXEL_FORMAT_LOSSES != 0 {
+            anyhow::bail!(
+                "The lossless {} output can't keep {} pixels, it would convert them into {}",
+                self.codec.name(),
+                pixel_format_name(input),
+                pixel_format_name(best),
+            );
+        }
+
+        Ok(best)
+    }
+
+    /// Whether the chosen profile allows the bit depth and chroma subsampling of the format.
+    /// Unknown profiles are left for the encoder to check.
+    fn profile_supports(&self, format: ffmpeg::format::Pixel) -> bool {
+        let Some(descriptor) = format.descriptor() else {
+            return false;
+        };
+        let depth = unsafe { (*descriptor.as_ptr()).comp[0].depth };
+        // Halved chroma widths and heights as powers of 2, so 4:2:0 is (1, 1) and 4:2:2 (1, 0).
+        let chroma = (descriptor.log2_chroma_w(), descriptor.log2_chroma_h());
+
+        let (max_depth, min_chroma) = match (self.codec, self.profile.as_deref()) {
+            (
+                VideoCodec::H264,
+                Some("baseline" | "constrained_baseline" | "main" | "extended" | "high"),
+            )
+            | (VideoCodec::H265, Some("main" | "mainstillpicture" | "msp")) => (8, (1, 1)),
+            (VideoCodec::H264, Some("high10")) | (VideoCodec::H265, Some("main10")) => (10, (1, 1)),
+            (VideoCodec::H265, Some("main12")) => (12, (1, 1)),
+            (VideoCodec::H264, Some("high422")) | (VideoCodec::H265, Some("main422-10")) => {
+                (10, (1, 0))
+            }
+            (VideoCodec::H265, Some("main422-12")) => (12, (1, 0)),
+            (VideoCodec::H265, Some("main444-8")) => (8, (0, 0)),
+            (VideoCodec::H265, Some("main444-10")) => (10, (0, 0)),
+            (VideoCodec::H264, Some("high444" | "high444p"))
+            | (VideoCodec::H265, Some("main444-12")) => (14, (0, 0)),
+            _ => return true,
+        };
+
+        // Profiles allowing less subsampling allow more of it too.
+        depth <= max_depth && chroma.0 >= min_chroma.0 && chroma.1 >= min_chroma.1
+    }
+
+    /// Whether re-encoded streams are identical to the input ones pixel by pixel.
+    pub fn is_lossless(&self) -> bool {
+        self.codec.is_lossless() || self.quality == Quality::Lossless
+    }
+
+    /// Returns the options for the stream, where [`Quality::MatchSource`] is replaced with the
+    /// bitrate or CRF chosen from the properties of the input stream.
+    pub(crate) fn resolve(&self, source: &VideoProperties) -> EncoderOptions {
+        let mut options = self.clone();
+        if options.quality == Quality::MatchSource {
+            options.quality = self.match_source(source);
+        }
+
+        options
+    }
+
+    fn match_source(&self, source: &VideoProperties) -> Quality {
+        let codec = self.codec;
+        // Sources of unknown quality are encoded a bit better than usual to lose nothing visible.
+        let high_quality = match codec.default_crf() {
+            Some(crf) => Quality::Crf(crf.saturating_sub(5)),
+            None => Quality::Bitrate(16_000_000),
+        };
+
+        let (Some(bit_rate), Some(relative_bit_rate)) =
+            (source.bit_rate, source_relative_bit_rate(source.codec))
+        else {
+            return high_quality;
+        };
+        if codec.is_lossless() {
+            return high_quality;
+        }
+
+        let bit_rate = bit_rate as f64 * codec.relative_bit_rate() / relative_bit_rate;
+
+        // Broken metadata may claim anything, so the bitrate is kept within sane bits per pixel.
+        let pixel_rate = f64::from(source.width)
+            * f64::from(source.height)
+            * source
+                .frame_rate
+                .map(f64::from)
+                .filter(|frame_rate| frame_rate.is_normal() && *frame_rate > 0.0)
+                .unwrap_or(30.0);
+        let bit_rate = bit_rate.clamp(
+            pixel_rate * 0.01 * codec.relative_bit_rate(),
+            pixel_rate * 0.5 * codec.relative_bit_rate(),
+        );
+
+        Quality::Bitrate(bit_rate.round() as usize)
+    }
+
+    /// Makes codec specific options of the encoder.
+    fn dictionary(&self, pass: Pass, stats_file: &Path) -> ffmpeg::Dictionary<'static> {
+        let mut dictionary = ffmpeg::Dictionary::new();
+
+        if let Some(preset) = &self.preset {
+            dictionary.set("preset", preset);
+        }
+
+        if let (Quality::Crf(crf), false) = (self.quality, self.codec.is_lossless()) {
+            dictionary.set("crf", &crf.to_string());
+        }
+
+        if let Some(profile) = &self.profile {
+            dictionary.set("profile", profile);
+        }
+
+        // libx265 takes the level and the lossless mode only among its own parameters.
+        let mut x265_params = vec![];
+
+        if let Some(level) = &self.level {
+            match self.codec {
+                VideoCodec::H265 => x265_params.push(format!("level-idc={level}")),
+                _ => dictionary.set("level", level),
+            }
+        }
+
+        if self.quality == Quality::Lossless {
+            match self.codec {
+                VideoCodec::H264 => dictionary.set("qp", "0"),
+                VideoCodec::H265 => x265_params.push(String::from("lossless=1")),
+                VideoCodec::Vp9 => dictionary.set("lossless", "1"),
+                _ => {}
+            }
+        }
+
+        if !x265_params.is_empty() {
+            dictionary.set("x265-params", &x265_params.join(":"));
+        }
+
+        if pass != Pass::Single {
+            dictionary.set("stats", &stats_file.to_string_lossy());
+        }
+
+        dictionary
+    }
+}
+
+/// Losses reported by FFmpeg which drop information of pixels: resolution, depth, colorspace,
+/// alpha, color quantization and chroma. Newer flags only tell that the format is larger than
+/// needed.
+const PIXEL_FORMAT_LOSSES: i32 = 0x3f;
+
+fn pixel_format_name(format: ffmpeg::format::Pixel) -> &'static str {
+    format
+        .descriptor()
+        .map_or("unknown", |descriptor| descriptor.name())
+}
+
+pub(crate) struct VideoDecoder(ffmpeg::codec::decoder::Video);
+
+impl VideoDecoder {
+    pub(crate) fn process_frames<F: FnMut(&mut ffmpeg::frame::Video) -> anyhow::Result<()>>(
+        &mut self,
+        mut processor: F,
+    ) -> anyhow::Result<()> {
+        let mut frame = ffmpeg::frame::Video::empty();
+        while self.0.receive_frame(&mut frame).is_ok() {
+            processor(&mut frame)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl_from!(ffmpeg::codec::decoder::Video => VideoDecoder);
+
+impl std::ops::Deref for VideoDecoder {
+    type Target = ffmpeg::codec::decoder::Video;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl std::ops::DerefMut for VideoDecoder {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// Bits per pixel of the VP8 bitrate ceiling in the constrained quality mode. It's a few times
+/// more than good quality needs, so the CRF is what decides.
+const VP8_MAX_BITS_PER_PIXEL: f64 = 0.3;
+
+/// Returns the bitrate ceiling of VP8 encoding with the CRF.
+fn vp8_max_bit_rate(width: u32, height: u32, frame_rate: Option<ffmpeg::Rational>) -> usize {
+    let frame_rate = frame_rate
+        .filter(|rate| rate.numerator() > 0 && rate.denominator() > 0)
+        .map_or(30.0, f64::from);
+
+    (f64::from(width) * f64::from(height) * frame_rate * VP8_MAX_BITS_PER_PIXEL) as usize
+}
+
+pub(crate) struct VideoEncoder(ffmpeg::codec::encoder::Video);
+
+impl VideoEncoder {
+    /// Makes the encoder of frames which come out of the filter of the decoder, so they have the
+    /// given size. Colors are described like the ones of the decoder, with the HDR metadata of
+    /// the input stream.
+    pub(crate) fn create_from_decoder(
+        decoder: &VideoDecoder,
+        filter: &mut Filter,
+        hdr_metadata: &HdrMetadata,
+        options: &EncoderOptions,
+        pass: Pass,
+        stats_file: &Path,
+        global_header: bool,
+    ) -> anyhow::Result<Self> {
+        let video = &decoder.0;
+
+        let output_codec = options.codec.encoder().ok_or(anyhow::anyhow!(
+            "The {} encoder is not available in the linked FFmpeg",
+            options.codec.name()
+        ))?;
+
+        let mut encoder_context = ffmpeg::codec::Context::new_with_codec(output_codec);
+        let mut flags = match pass {
+            Pass::Single => ffmpeg::codec::Flags::empty(),
+            Pass::First => ffmpeg::codec::Flags::PASS1,
+            Pass::Second => ffmpeg::codec::Flags::PASS2,
+        };
+        if global_header {
+            flags |= ffmpeg::codec::Flags::GLOBAL_HEADER;
+        }
+        encoder_context.set_flags(flags);
+        let mut encoder = encoder_context.encoder().video()?;
+
+        let (width, height) = filter.output_size();
+        encoder.set_width(width);
+        encoder.set_height(height);
+        encoder.set_aspect_ratio(filter.sample_aspect_ratio());
+        encoder.set_format(filter.pixel_format());
+        encoder.set_frame_rate(filter.frame_rate());
+        if let Some(max_denominator) = options.codec.max_time_base_denominator() {
+            filter.limit_time_base(max_denominator)?;
+        }
+        encoder.set_time_base(filter.time_base()?);
+
+        // Without them players guess BT.709, which washes out HLG and PQ videos. Converting the
+        // pixel format may change the range, so it's the one of the filtered frames.
+        let (color_space, color_range) = filter.colors()?;
+        encoder.set_colorspace(color_space);
+        encoder.set_color_range(color_range);
+        unsafe {
+            let context = encoder.as_mut_ptr();
+            (*context).color_primaries = video.color_primaries().into();
+            (*context).color_trc = video.color_transfer_characteristic().into();
+            (*context).chroma_sample_location = (*video.as_ptr()).chroma_sample_location;
+
+            // Encoders write the side data given to them as decoded into their headers.
+            for (_, kind, data) in hdr_metadata.side_data() {
+                let side_data = ffmpeg::ffi::av_frame_side_data_new(
+                    &mut (*context).decoded_side_data,
+                    &mut (*context).nb_decoded_side_data,
+                    kind.into(),
+                    data.len(),
+                    0,
+                );
+                if side_data.is_null() {
+                    anyhow::bail!("Cannot allocate HDR metadata for the encoder");
+                }
+                std::ptr::copy_nonoverlapping(data.as_ptr(), (*side_data).data, data.len());
+            }
+        }
+
+        match options.quality {
+            _ if options.codec.is_lossless() => {}
+            // libvpx is in the constrained quality mode unless the bitrate is zero.
+            Quality::Crf(_) if options.codec == VideoCodec::Vp9 => encoder.set_bit_rate(0),
+            // VP8 has no such mode, and the default ceiling of 256 kbit/s starves the CRF.
+            Quality::Crf(_) if options.codec == VideoCodec::Vp8 => {
+                encoder.set_bit_rate(vp8_max_bit_rate(width, height, filter.frame_rate()))
+            }
+            Quality::Crf(_) | Quality::Lossless => {}
+            Quality::Bitrate(bit_rate) => encoder.set_bit_rate(bit_rate),
+            Quality::MatchSource => anyhow::bail!("The quality of the encoder isn't resolved"),
+        }
+
+        Ok(encoder
+            .open_with(options.dictionary(pass, stats_file))?
+            .into())
+    }
+
+    pub(crate) fn process_packets<F: FnMut(&mut ffmpeg::Packet) -> Result<(), ffmpeg::Error>>(
+        &mut self,
+        mut processor: F,
+    ) -> Result<(), ffmpeg::Error> {
+        let mut packet = ffmpeg::Packet::empty();
+        while self.0.receive_packet(&mut packet).is_ok() {
+            processor(&mut packet)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl_from!(ffmpeg::codec::encoder::Video => VideoEncoder);
+
+impl std::ops::Deref for VideoEncoder {
+    type Target = ffmpeg::codec::encoder::Video;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl std::ops::DerefMut for VideoEncoder {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl From<&VideoEncoder> for ffmpeg::codec::Parameters {
+    fn from(value: &VideoEncoder) -> Self {
+        (&value.0).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ffmpeg::format::Pixel;
+
+    #[test]
+    fn lossless_rejects_lossy_pixel_formats() {
+        let ffv1 = |max_compatibility| EncoderOptions {
+            max_compatibility,
+            ..EncoderOptions::new(VideoCodec::Ffv1)
+        };
+        assert_eq!(
+            ffv1(false).pixel_format(Pixel::YUV422P10LE).unwrap(),
+            Pixel::YUV422P10LE
+        );
+        assert_eq!(
+            ffv1(true).pixel_format(Pixel::YUV420P).unwrap(),
+            Pixel::YUV420P
+        );
+        assert!(ffv1(true).pixel_format(Pixel::YUV422P10LE).is_err());
+
+        let h264 = |quality| EncoderOptions {
+            quality,
+            profile: Some(String::from("high")),
+            ..EncoderOptions::new(VideoCodec::H264)
+        };
+        assert!(
+            h264(Quality::Lossless)
+                .pixel_format(Pixel::YUV444P)
+                .is_err()
+        );
+        assert_eq!(
+            h264(Quality::Lossless)
+                .pixel_format(Pixel::YUV420P)
+                .unwrap(),
+            Pixel::YUV420P
+        );
+        // Lossy encoding is allowed to convert.
+        assert_eq!(
+            h264(Quality::Crf(23)).pixel_format(Pixel::YUV444P).unwrap(),
+            Pixel::YUV420P
+        );
+    }
+
+    fn with_profile(codec: VideoCodec, profile: Option<&str>) -> EncoderOptions {
+        EncoderOptions {
+            profile: profile.map(String::from),
+            ..EncoderOptions::new(codec)
+        }
+    }
+
+    #[test]
+    fn profiles_limit_pixel_formats() {
+        let supports =
+            |codec, profile, format| with_profile(codec, profile).profile_supports(format);
+
+        assert!(supports(VideoCodec::H264, Some("high"), Pixel::YUV420P));
+        assert!(!supports(VideoCodec::H264, Some("high"), Pixel::YUV422P));
+        assert!(!supports(
+            VideoCodec::H264,
+            Some("high"),
+            Pixel::YUV420P10LE
+        ));
+        assert!(supports(
+            VideoCodec::H264,
+            Some("high10"),
+            Pixel::YUV420P10LE
+        ));
+        assert!(!supports(
+            VideoCodec::H264,
+            Some("high10"),
+            Pixel::YUV422P10LE
+        ));
+        // Profiles allowing less subsampling allow more of it too.
+        assert!(supports(
+            VideoCodec::H264,
+            Some("high422"),
+            Pixel::YUV422P10LE
+        ));
+        assert!(supports(VideoCodec::H264, Some("high422"), Pixel::YUV420P));
+        assert!(!supports(VideoCodec::H264, Some("high422"), Pixel::YUV444P));
+        assert!(supports(
+            VideoCodec::H265,
+            Some("main444-10"),
+            Pixel::YUV444P10LE
+        ));
+        assert!(!supports(
+            VideoCodec::H265,
+            Some("main444-10"),
+            Pixel::YUV444P12LE
+        ));
+        // Unknown profiles are left for the encoder.
+        assert!(supports(VideoCodec::H264, None, Pixel::YUV444P10LE));
+        assert!(supports(
+            VideoCodec::H265,
+            Some("future"),
+            Pixel::YUV444P12LE
+        ));
+    }
+
+    #[test]
+    fn pixel_format_of_profiles() {
+        assert_eq!(
+            with_profile(VideoCodec::H264, None)
+                .pixel_format(Pixel::YUV422P)
+                .unwrap(),
+            Pixel::YUV422P
+        );
+        assert_eq!(
+            with_profile(VideoCodec::H264, Some("high"))
+                .pixel_format(Pixel::YUV422P)
+                .unwrap(),
+            Pixel::YUV420P
+        );
+        assert_eq!(
+            EncoderOptions {
+                max_compatibility: true,
+                ..EncoderOptions::default()
+            }
+            .pixel_format(Pixel::YUV420P10LE)
+            .unwrap(),
+            Pixel::YUV420P
+        );
+    }
+
+    #[test]
+    fn validate_options() {
+        let options = |codec, quality| EncoderOptions {
+            quality,
+            ..EncoderOptions::new(codec)
+        };
+
+        assert!(
+            options(VideoCodec::H264, Quality::Crf(0))
+                .validate()
+                .is_ok()
+        );
+        assert!(
+            options(VideoCodec::H264, Quality::Crf(51))
+                .validate()
+                .is_ok()
+        );
+        assert!(
+            options(VideoCodec::H264, Quality::Crf(52))
+                .validate()
+                .is_err()
+        );
+        assert!(
+            options(VideoCodec::H264, Quality::Lossless)
+                .validate()
+                .is_ok()
+        );
+        // MPEG-4 has only the bitrate, while FFV1 ignores the quality.
+        assert!(
+            options(VideoCodec::Mpeg4, Quality::Crf(20))
+                .validate()
+                .is_err()
+        );
+        assert!(
+            options(VideoCodec::Mpeg4, Quality::Lossless)
+                .validate()
+                .is_err()
+        );
+        assert!(
+            options(VideoCodec::Mpeg4, Quality::Bitrate(4_000_000))
+                .validate()
+                .is_ok()
+        );
+        assert!(
+            options(VideoCodec::Ffv1, Quality::Crf(20))
+                .validate()
+                .is_ok()
+        );
+
+        let two_pass = |quality| EncoderOptions {
+            two_pass: true,
+            ..options(VideoCodec::H264, quality)
+        };
+        assert!(two_pass(Quality::Bitrate(4_000_000)).validate().is_ok());
+        assert!(two_pass(Quality::MatchSource).validate().is_ok());
+        assert!(two_pass(Quality::Crf(23)).validate().is_err());
+        assert!(
+            EncoderOptions {
+                two_pass: true,
+                ..options(VideoCodec::Mpeg4, Quality::Bitrate(4_000_000))
+            }
+            .validate()
+            .is_err()
+        );
+
+        assert!(
+            EncoderOptions {
+                preset: Some(String::from("medium")),
+                ..EncoderOptions::new(VideoCodec::Mpeg4)
+            }
+            .validate()
+            .is_err()
+        );
+        assert!(
+            with_profile(VideoCodec::Mpeg4, Some("main"))
+                .validate()
+                .is_err()
+        );
+    }
+
+    fn properties(codec: ffmpeg::codec::Id, bit_rate: Option<usize>) -> VideoProperties {
+        VideoProperties {
+            codec,
+            width: 1920,
+            height: 1080,
+            frame_rate: Some(ffmpeg::Rational::new(30, 1)),
+            bit_rate,
+        }
+    }
+
+    #[test]
+    fn resolve_matching_the_source() {
+        use ffmpeg::codec::Id;
+
+        let resolve = |codec, source| {
+            EncoderOptions {
+                quality: Quality::MatchSource,
+                ..EncoderOptions::new(codec)
+            }
+            .resolve(&source)
+            .quality
+        };
+
+        assert_eq!(
+            resolve(VideoCodec::H264, properties(Id::H264, Some(8_000_000))),
+            Quality::Bitrate(8_000_000)
+        );
+        // More efficient codecs need fewer bits, less efficient ones more.
+        assert_eq!(
+            resolve(VideoCodec::H265, properties(Id::H264, Some(8_000_000))),
+            Quality::Bitrate(4_800_000)
+        );
+        assert_eq!(
+            resolve(
+                VideoCodec::H264,
+                properties(Id::MPEG2VIDEO, Some(20_000_000))
+            ),
+            Quality::Bitrate(10_000_000)
+        );
+        // Broken bitrates are kept within 0.01 bits per pixel at 30 fps.
+        assert_eq!(
+            resolve(VideoCodec::H264, properties(Id::H264, Some(1))),
+            Quality::Bitrate(622_080)
+        );
+        // Sources of unknown quality are encoded better than usual.
+        assert_eq!(
+            resolve(VideoCodec::H264, properties(Id::H264, None)),
+            Quality::Crf(18)
+        );
+        assert_eq!(
+            resolve(VideoCodec::H264, properties(Id::PRORES, Some(100_000_000))),
+            Quality::Crf(18)
+        );
+        assert_eq!(
+            resolve(VideoCodec::Mpeg4, properties(Id::PRORES, None)),
+            Quality::Bitrate(16_000_000)
+        );
+
+        // Other qualities are kept as they are.
+        assert_eq!(
+            EncoderOptions::new(VideoCodec::H264)
+                .resolve(&properties(Id::H264, Some(8_000_000)))
+                .quality,
+            Quality::Crf(23)
+        );
+    }
+
+    #[test]
+    fn parse_codecs() {
+        let parse = |codec: &str| codec.parse::<VideoCodec>().unwrap();
+
+        assert_eq!(parse("h264"), VideoCodec::H264);
+        assert_eq!(parse("h265"), VideoCodec::H265);
+        assert_eq!(parse("hevc"), VideoCodec::H265);
+        assert_eq!(parse("vp9"), VideoCodec::Vp9);
+        assert_eq!(parse("ffv1"), VideoCodec::Ffv1);
+        assert!("H.264".parse::<VideoCodec>().is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use ffmpeg_next as ffmpeg;
+
+use crate::rotate::Rotate;
+
+/// Whether the stream is a picture attached to the file, e.g. the cover art of MP4 and MKV files,
+/// rather than a real video.
+pub(crate) fn is_attached_picture(stream: &ffmpeg::Stream) -> bool {
+    stream
+        .disposition()
+        .contains(ffmpeg::format::stream::Disposition::ATTACHED_PIC)
+}
+
+/// Decodes the attached picture of the stream, rotates it and encodes it again with the same
+/// codec. Returns the parameters of the new picture with its only packet.
+pub(crate) fn rotate_attached_picture(
+    stream: &ffmpeg::Stream,
+    rotate: &Rotate,
+) -> anyhow::Result<(ffmpeg::codec::Parameters, ffmpeg::Packet)> {
+    let picture = unsafe {
+        let picture = &(*stream.as_ptr()).attached_pic;
+        if picture.data.is_null() || picture.size <= 0 {
+            anyhow::bail!("The stream #{} has no attached picture", stream.index());
+        }
+        ffmpeg::Packet::copy(std::slice::from_raw_parts(
+            picture.data,
+            picture.size as usize,
+        ))
+    };
+
+    let decoder_context = ffmpeg::codec::Context::from_parameters(stream.parameters())?;
+    let mut decoder = decoder_context.decoder().video()?;
+    decoder.send_packet(&picture)?;
+    decoder.send_eof()?;
+    let mut frame = ffmpeg::frame::Video::empty();
+    decoder.receive_frame(&mut frame)?;
+
+    let codec = ffmpeg::encoder::find(stream.parameters().id()).ok_or(anyhow::anyhow!(
+        "No encoder for the {} attached picture",
+        stream.parameters().id().name()
+    ))?;
+    let pixel_format = best_pixel_format(&codec, frame.format())?;
+
+    let mut frame = rotate_frame(&frame, rotate, pixel_format)?;
+    frame.set_pts(Some(0));
+
+    let mut encoder = ffmpeg::codec::Context::new_with_codec(codec)
+        .encoder()
+        .video()?;
+    encoder.set_width(frame.width());
+    encoder.set_height(frame.height());
+    encoder.set_format(pixel_format);
+    encoder.set_aspect_ratio(frame.aspect_ratio());
+    encoder.set_color_range(frame.color_range());
+    encoder.set_time_base(ffmpeg::Rational::new(1, 1));
+    let mut encoder = encoder.open()?;
+
+    encoder.send_frame(&frame)?;
+    encoder.send_eof()?;
+    let mut packet = ffmpeg::Packet::empty();
+    encoder.receive_packet(&mut packet)?;
+    packet.set_flags(ffmpeg::packet::Flags::KEY);
+
+    Ok(((&encoder).into(), packet))
+}
+
+/// Returns the pixel format of the encoder closest to the one of the picture.
+fn best_pixel_format(
+    codec: &ffmpeg::Codec,
+    input: ffmpeg::format::Pixel,
+) -> anyhow::Result<ffmpeg::format::Pixel> {
+    let Some(formats) = codec.video()?.formats() else {
+        return Ok(input);
+    };
+
+    let mut formats: Vec<ffmpeg::ffi::AVPixelFormat> = formats.map(Into::into).collect();
+    formats.push(ffmpeg::ffi::AVPixelFormat::AV_PIX_FMT_NONE);
+    let best = unsafe {
+        ffmpeg::ffi::avcodec_find_best_pix_fmt_of_list(
+            formats.as_ptr(),
+            input.into(),
+            0,
+            std::ptr::null_mut(),
+        )
+    };
+    Ok(best.into())
+}
+
+/// Passes the picture through the rotation and converts it into the pixel format.
+fn rotate_frame(
+    frame: &ffmpeg::frame::Video,
+    rotate: &Rotate,
+    pixel_format: ffmpeg::format::Pixel,
+) -> anyhow::Result<ffmpeg::frame::Video> {
+    let mut filter_graph = ffmpeg::filter::Graph::new();
+
+    let name = |format: ffmpeg::format::Pixel| {
+        format
+            .descriptor()
+            .map(|descriptor| descriptor.name())
+            .ok_or(anyhow::anyhow!(
+                "Unknown pixel format of the attached picture"
+            ))
+    };
+    let sample_aspect_ratio = match frame.aspect_ratio() {
+        ratio if ratio.numerator() > 0 && ratio.denominator() > 0 => ratio,
+        _ => ffmpeg::Rational::new(1, 1),
+    };
+    let filter_args = format!(
+        "video_size={}x{}:pix_fmt={}:time_base=1/1:pixel_aspect={}/{}",
+        frame.width(),
+        frame.height(),
+        name(frame.format())?,
+        sample_aspect_ratio.numerator(),
+        sample_aspect_ratio.denominator(),
+    );
+
+    filter_graph.add(&ffmpeg::filter::find("buffer").unwrap(), "in", &filter_args)?;
+    filter_graph.add(&ffmpeg::filter::find("buffersink").unwrap(), "out", "")?;
+    filter_graph
+        .output("in", 0)?
+        .input("out", 0)?
+        .parse(&format!(
+            "{},format=pix_fmts={}",
+            rotate.as_filter(),
+            name(pixel_format)?
+        ))?;
+    filter_graph.validate()?;
+
+    filter_graph
+        .get("in")
+        .ok_or(anyhow::anyhow!("Found missing input of filter graph!"))?
+        .source()
+        .add(frame)?;
+    filter_graph
+        .get("in")
+        .ok_or(anyhow::anyhow!("Found missing input of filter graph!"))?
+        .source()
+        .flush()?;
+
+    let mut rotated = ffmpeg::frame::Video::empty();
+    filter_graph
+        .get("out")
+        .ok_or(anyhow::anyhow!("Found missing output for a filter!"))?
+        .sink()
+        .frame(&mut rotated)?;
+    Ok(rotated)
+}
@@ -0,0 +1,345 @@
+use ffmpeg_next as ffmpeg;
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    StreamId,
+    codec::{EncoderOptions, Pass, Quality, VideoCodec, VideoEncoder},
+    cover::{is_attached_picture, rotate_attached_picture},
+    filter::Filter,
+    rotate::{Method, Rotate, display_rotation, set_display_matrix},
+    source::{HdrMetadata, Source},
+    track::copy_track,
+    transform::{Deinterlace, Transform},
+};
+
+pub(crate) struct Destination {
+    pub(crate) output_file: PathBuf,
+    pub(crate) output_ctx: ffmpeg::format::context::Output,
+    pub(crate) filters: HashMap<StreamId, Filter>,
+    pub(crate) encoders: HashMap<StreamId, VideoEncoder>,
+    /// Rate control chosen for every re-encoded stream.
+    pub(crate) qualities: HashMap<StreamId, Quality>,
+    /// Output streams of input streams, dropped streams have none.
+    pub(crate) output_streams: HashMap<StreamId, StreamId>,
+    /// Rotated attached pictures, which replace the packets of their streams.
+    pub(crate) attached_pictures: HashMap<StreamId, ffmpeg::Packet>,
+    pub(crate) time_bases: HashMap<StreamId, ffmpeg::Rational>,
+}
+
+impl Destination {
+    pub(crate) fn create<Output: AsRef<Path>>(
+        output: Output,
+        source: &Source,
+        transform: &Transform,
+        method: &Method,
+        encoder_options: &EncoderOptions,
+        pass: Pass,
+    ) -> anyhow::Result<Self> {
+        validate(source, transform, method, encoder_options)?;
+
+        // The file exists from now on, so it's removed if anything else fails.
+        let output_ctx = ffmpeg::format::output(output.as_ref())?;
+        Self::open(
+            output.as_ref(),
+            output_ctx,
+            source,
+            transform,
+            method,
+            encoder_options,
+            pass,
+        )
+        .inspect_err(|_| {
+            let _ = std::fs::remove_file(output.as_ref());
+        })
+    }
+
+    /// Adds streams of the source to the opened output file.
+    fn open(
+        output: &Path,
+        mut output_ctx: ffmpeg::format::context::Output,
+        source: &Source,
+        transform: &Transform,
+        method: &Method,
+        encoder_options: &EncoderOptions,
+        pass: Pass,
+    ) -> anyhow::Result<Self> {
+        output_ctx.set_metadata(source.input_ctx.metadata().to_owned());
+
+        if !source.decoders.is_empty() {
+            check_container(&output_ctx, encoder_options.codec)?;
+        }
+        let global_header = output_ctx
+            .format()
+            .flags()
+            .contains(ffmpeg::format::Flags::GLOBAL_HEADER);
+
+        let mut filters = HashMap::new();
+        let mut encoders = HashMap::new();
+        let mut qualities = HashMap::new();
+        let mut output_streams = HashMap::new();
+        let mut attached_pictures = HashMap::new();
+
+        for (index, input_stream) in source.input_ctx.streams().enumerate() {
+            let Some(output_index) = transform.output_index(index) else {
+                continue;
+            };
+            output_streams.insert(index.into(), output_index.into());
+
+            if let Some(decoder) = source.decoders.get(&index.into()) {
+                let options = encoder_options.resolve(&source.properties[&index.into()]);
+                // Streams with the constant quality don't need statistics.
+                let pass = match options.quality {
+                    Quality::Bitrate(_) => pass,
+                    _ => Pass::Single,
+                };
+
+                // The input orientation is physically applied together with the requested
+                // transform, so the output doesn't need the display matrix anymore.
+                let pixel_format = options.pixel_format(decoder.format())?;
+                let mut filter = Filter::create(source, &index.into(), transform, pixel_format)?;
+                let hdr_metadata = source.hdr_metadata(&index.into());
+                let encoder = VideoEncoder::create_from_decoder(
+                    decoder,
+                    &mut filter,
+                    &hdr_metadata,
+                    &options,
+                    pass,
+                    &stats_file(output.as_ref(), index),
+                    global_header,
+                )?;
+                let codec = encoder.codec().ok_or(anyhow::anyhow!(
+                    "Unknown codec. The encoder was wrongly configured."
+                ))?;
+                let mut output_stream = output_ctx.add_stream(codec)?;
+                output_stream.set_parameters(&encoder);
+                set_display_matrix(&mut output_stream.parameters(), 0.0, false)?;
+                // Some muxers take the shape of pixels from the stream rather than the codec.
+                unsafe {
+                    (*output_stream.as_mut_ptr()).sample_aspect_ratio =
+                        filter.sample_aspect_ratio().into();
+                }
+                set_hdr_metadata(&mut output_stream.parameters(), &hdr_metadata)?;
+                copy_track(&mut output_stream, &input_stream, transform.track(index));
+
+                filters.insert(index.into(), filter);
+                encoders.insert(index.into(), encoder);
+                qualities.insert(index.into(), options.quality);
+            } else if is_attached_picture(&input_stream)
+                && transform.rotate_attached_pictures
+                && transform.rotate != Rotate::Deg0
+            {
+                let (parameters, picture) =
+                    rotate_attached_picture(&input_stream, &transform.rotate)?;
+                let mut output_stream =
+                    output_ctx.add_stream(ffmpeg::encoder::find(ffmpeg::codec::Id::None))?;
+                output_stream.set_parameters(parameters);
+                unsafe {
+                    (*output_stream.parameters().as_mut_ptr()).codec_tag = 0;
+                }
+                copy_track(&mut output_stream, &input_stream, transform.track(index));
+
+                attached_pictures.insert(index.into(), picture);
+            } else {
+                // Set up for stream copy for non-video stream.
+                let mut output_stream =
+                    output_ctx.add_stream(ffmpeg::encoder::find(ffmpeg::codec::Id::None))?;
+                output_stream.set_parameters(input_stream.parameters());
+                copy_track(&mut output_stream, &input_stream, transform.track(index));
+                // We need to set codec_tag to 0 lest we run into incompatible codec tag
+                // issues when muxing into a different container format. Unfortunately
+                // there's no high level API to do this (yet).
+                unsafe {
+                    (*output_stream.parameters().as_mut_ptr()).codec_tag = 0;
+                }
+
+                // Attached pictures and video streams which aren't chosen are left as they are.
+                if input_stream.parameters().medium() == ffmpeg::media::Type::Video
+                    && !is_attached_picture(&input_stream)
+                    && transform.applies_to(index)
+                {
+                    match method {
+                        Method::Metadata => {
+                            let orientation = source.orientation(&index.into());
+                            let rotate = &transform.rotate;
+                            let (degrees, hflip) =
+                                if orientation.is_mirrored() || rotate.is_mirrored() {
+                                    orientation.then(rotate).display_matrix()
+                                } else {
+                                    // Slight tilts of the input are kept as is.
+                                    let current_rotation =
+                                        display_rotation(&input_stream.parameters()).unwrap_or(0.0);
+                                    (current_rotation + rotate.degrees(), false)
+                                };
+                            set_display_matrix(&mut output_stream.parameters(), degrees, hflip)?;
+                        }
+                        // The stream is already upright, so only leftovers of the matrix
+                        // (e.g. slight tilts) are dropped.
+                        Method::BakeIn => {
+                            set_display_matrix(&mut output_stream.parameters(), 0.0, false)?
+                        }
+                        Method::Reencode => {}
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            output_file: output.as_ref().to_owned(),
+            output_ctx,
+            filters,
+            encoders,
+            qualities,
+            output_streams,
+            attached_pictures,
+            // INFO: it's unknown until begin of writing into a file
+            time_bases: HashMap::new(),
+        })
+    }
+
+    pub(crate) fn write_header(&mut self) -> anyhow::Result<()> {
+        ffmpeg::format::context::output::dump(&self.output_ctx, 0, self.output_file.to_str());
+        self.output_ctx.write_header()?;
+        Ok(())
+    }
+
+    pub(crate) fn write_trailer(&mut self) -> anyhow::Result<()> {
+        self.output_ctx.write_trailer()?;
+        Ok(())
+    }
+
+    /// Closes the unfinished output file and removes it. Nothing can be written afterwards.
+    pub(crate) fn discard(&mut self) -> std::io::Result<()> {
+        unsafe {
+            ffmpeg::ffi::avio_closep(&mut (*self.output_ctx.as_mut_ptr()).pb);
+        }
+
+        std::fs::remove_file(&self.output_file)
+    }
+
+    /// Removes statistics left by the first pass of encoders.
+    pub(crate) fn remove_stats_files(&self) {
+        for id in self.encoders.keys() {
+            let stats_file = stats_file(&self.output_file, id.0);
+            let mut mbtree_file = stats_file.clone().into_os_string();
+            mbtree_file.push(".mbtree");
+
+            // Files are missing if the first pass has failed.
+            let _ = std::fs::remove_file(stats_file);
+            let _ = std::fs::remove_file(mbtree_file);
+        }
+    }
+
+    pub(crate) fn setup_time_bases(&mut self) -> anyhow::Result<()> {
+        for (input_id, output_id) in &self.output_streams {
+            let output_stream = self
+                .output_ctx
+                .stream(output_id.0)
+                .ok_or(anyhow::anyhow!("Found missing stream in destination."))?;
+            self.time_bases.insert(*input_id, output_stream.time_base());
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks the settings which don't need the output file, so it isn't even created when they're
+/// wrong.
+fn validate(
+    source: &Source,
+    transform: &Transform,
+    method: &Method,
+    encoder_options: &EncoderOptions,
+) -> anyhow::Result<()> {
+    if transform.tilt.is_some() && *method == Method::Metadata {
+        anyhow::bail!("Tilting by an arbitrary angle needs re-encoding");
+    }
+    if !transform.segments.is_empty() && *method == Method::Metadata {
+        anyhow::bail!("Rotating segments of the video needs re-encoding");
+    }
+    if !transform.operations.is_empty() && *method == Method::Metadata {
+        anyhow::bail!("Operations on frames need re-encoding");
+    }
+    if transform.scale.is_some() && *method == Method::Metadata {
+        anyhow::bail!("Scaling needs re-encoding");
+    }
+    if transform.deinterlace == Deinterlace::Always && *method == Method::Metadata {
+        anyhow::bail!("Deinterlacing needs re-encoding");
+    }
+
+    if !source.decoders.is_empty() {
+        encoder_options.validate()?;
+    }
+    // Lossless encoding can't be kept if the pixel format drops depth or chroma of the input.
+    for decoder in source.decoders.values() {
+        encoder_options.pixel_format(decoder.format())?;
+    }
+
+    Ok(())
+}
+
+/// Stores the HDR metadata in the stream parameters, so muxers write it into the container too.
+fn set_hdr_metadata(
+    parameters: &mut ffmpeg::codec::Parameters,
+    hdr_metadata: &HdrMetadata,
+) -> anyhow::Result<()> {
+    for (kind, _, data) in hdr_metadata.side_data() {
+        unsafe {
+            let parameters = parameters.as_mut_ptr();
+            let side_data = ffmpeg::ffi::av_packet_side_data_new(
+                &mut (*parameters).coded_side_data,
+                &mut (*parameters).nb_coded_side_data,
+                kind.into(),
+                data.len(),
+                0,
+            );
+            if side_data.is_null() {
+                anyhow::bail!("Cannot allocate HDR metadata for the output stream");
+            }
+            std::ptr::copy_nonoverlapping(data.as_ptr(), (*side_data).data, data.len());
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the file where the encoder of the stream keeps statistics between passes.
+fn stats_file(output: &Path, stream_index: usize) -> PathBuf {
+    let mut file_name = output.file_name().unwrap_or_default().to_owned();
+    file_name.push(format!(".{stream_index}.passlog"));
+
+    output.with_file_name(file_name)
+}
+
+/// Checks that the output container can store streams of the codec.
+fn check_container(
+    output_ctx: &ffmpeg::format::context::Output,
+    codec: VideoCodec,
+) -> anyhow::Result<()> {
+    let encoder = codec.encoder().ok_or(anyhow::anyhow!(
+        "The {} encoder is not available in the linked FFmpeg",
+        codec.name()
+    ))?;
+    let format = output_ctx.format();
+
+    let supported = unsafe {
+        ffmpeg::ffi::avformat_query_codec(
+            format.as_ptr(),
+            encoder.id().into(),
+            ffmpeg::codec::Compliance::Normal.into(),
+        )
+    };
+    // Negative values mean that the muxer doesn't know, so let it try.
+    if supported == 0 {
+        anyhow::bail!(
+            "The {} container doesn't support {} video",
+            format.name(),
+            codec.name()
+        );
+    }
+
+    Ok(())
+}
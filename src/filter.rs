@@ -0,0 +1,535 @@
+use ffmpeg_next as ffmpeg;
+
+use ffmpeg::Rescale;
+
+use std::time::Duration;
+
+use crate::{
+    StreamId,
+    codec::VideoDecoder,
+    rotate::round_to_even,
+    source::Source,
+    transform::{Geometry, Operation, Transform, square_pixels},
+};
+
+pub(crate) struct Filter {
+    /// The graph of the main rotation followed by the graphs of segments, in the order of
+    /// [`Transform::segments`].
+    filter_graphs: Vec<ffmpeg::filter::Graph>,
+    transform: Transform,
+    /// The geometry of frames coming out of every graph.
+    output: Geometry,
+    /// The pixel format frames are converted into at the end of every graph.
+    pixel_format: ffmpeg::format::Pixel,
+    /// The time base in which frames come to the graphs. It's the one of the input stream unless
+    /// the deinterlacer changes it.
+    time_base: ffmpeg::Rational,
+    /// The time base frames are moved to when they come out, if the encoder can't take the one
+    /// of the graphs.
+    output_time_base: Option<ffmpeg::Rational>,
+    /// The timestamp the last frame came out with and the one it was given, both in the output
+    /// time base.
+    last_pts: Option<(i64, i64)>,
+    /// The graph the last frame was sent to.
+    current: usize,
+    /// The graph deinterlacing frames before they're sent to the graphs above, if the stream is
+    /// interlaced.
+    deinterlacer: Option<ffmpeg::filter::Graph>,
+}
+
+impl Filter {
+    /// Makes the filter applying the transform to the decoded stream of the source and
+    /// converting frames into the pixel format.
+    pub(crate) fn create(
+        source: &Source,
+        stream_id: &StreamId,
+        transform: &Transform,
+        pixel_format: ffmpeg::format::Pixel,
+    ) -> anyhow::Result<Self> {
+        let decoder = source
+            .decoders
+            .get(stream_id)
+            .ok_or(anyhow::anyhow!("The stream #{} isn't decoded", stream_id.0))?;
+        let stream = source
+            .input_ctx
+            .stream(stream_id.0)
+            .ok_or(anyhow::anyhow!("Found missing stream in source."))?;
+        let orientation = source.orientation(stream_id);
+
+        let input = Geometry {
+            width: decoder.width(),
+            height: decoder.height(),
+            sample_aspect_ratio: source.sample_aspect_ratio(stream_id),
+            frame_rate: source
+                .properties
+                .get(stream_id)
+                .and_then(|properties| properties.frame_rate),
+            start_time: source.start_time(),
+        };
+        // Segments are told apart after deinterlacing, as it needs frames around every one.
+        let (deinterlacer, time_base) = match transform
+            .deinterlace
+            .as_filter(source.is_interlaced(stream_id))
+        {
+            Some(deinterlace) => {
+                let (deinterlacer, time_base) =
+                    create_deinterlacer(decoder, stream.time_base(), input, &deinterlace)?;
+                (Some(deinterlacer), time_base)
+            }
+            None => (None, stream.time_base()),
+        };
+
+        let operations = transform.operations(&orientation, None);
+        let (filter_graph, output) =
+            create_graph(decoder, time_base, input, &operations, None, pixel_format)?;
+
+        let mut filter_graphs = vec![];
+        let output = if transform.segments.is_empty() {
+            filter_graphs.push(filter_graph);
+            output
+        } else {
+            // Segments may have another size, so all frames are fitted into the same one, which
+            // is the size of the main rotation unless it's chosen.
+            let canvas = match transform.canvas {
+                Some((width, height)) => (
+                    round_to_even(f64::from(width)),
+                    round_to_even(f64::from(height)),
+                ),
+                None => (output.width, output.height),
+            };
+
+            let (filter_graph, output) = create_graph(
+                decoder,
+                time_base,
+                input,
+                &operations,
+                Some(canvas),
+                pixel_format,
+            )?;
+            filter_graphs.push(filter_graph);
+            for segment in 0..transform.segments.len() {
+                let operations = transform.operations(&orientation, Some(segment));
+                let (filter_graph, _) = create_graph(
+                    decoder,
+                    time_base,
+                    input,
+                    &operations,
+                    Some(canvas),
+                    pixel_format,
+                )?;
+                filter_graphs.push(filter_graph);
+            }
+
+            output
+        };
+
+        Ok(Self {
+            filter_graphs,
+            transform: transform.clone(),
+            output,
+            pixel_format,
+            time_base,
+            output_time_base: None,
+            last_pts: None,
+            current: 0,
+            deinterlacer,
+        })
+    }
+
+    /// Returns the size of frames coming out of the filter.
+    pub(crate) fn output_size(&self) -> (u32, u32) {
+        (self.output.width, self.output.height)
+    }
+
+    /// Returns the width of output pixels relative to their height.
+    pub(crate) fn sample_aspect_ratio(&self) -> ffmpeg::Rational {
+        self.output.sample_aspect_ratio
+    }
+
+    /// Returns the pixel format of frames coming out of the filter.
+    pub(crate) fn pixel_format(&self) -> ffmpeg::format::Pixel {
+        self.pixel_format
+    }
+
+    /// Returns the color space and range of frames coming out of the filter, which converting
+    /// the pixel format may change.
+    pub(crate) fn colors(
+        &mut self,
+    ) -> anyhow::Result<(ffmpeg::color::Space, ffmpeg::color::Range)> {
+        let sink = self.filter_graphs[0]
+            .get("out")
+            .ok_or(anyhow::anyhow!("Found missing output for a filter!"))?;
+
+        let (color_space, color_range) = unsafe {
+            (
+                ffmpeg::ffi::av_buffersink_get_colorspace(sink.as_ptr()),
+                ffmpeg::ffi::av_buffersink_get_color_range(sink.as_ptr()),
+            )
+        };
+        Ok((color_space.into(), color_range.into()))
+    }
+
+    /// Returns the frame rate of the output, if it's known.
+    pub(crate) fn frame_rate(&self) -> Option<ffmpeg::Rational> {
+        self.output.frame_rate
+    }
+
+    /// Returns the time base of frames coming out of the filter.
+    pub(crate) fn time_base(&mut self) -> anyhow::Result<ffmpeg::Rational> {
+        match self.output_time_base {
+            Some(time_base) => Ok(time_base),
+            None => self.graph_time_base(),
+        }
+    }
+
+    /// Moves frames to a coarser time base if the one of the graphs has a larger denominator,
+    /// since some encoders can't store it. It's the inverse frame rate when it's known, as
+    /// FFmpeg does, otherwise milliseconds.
+    pub(crate) fn limit_time_base(&mut self, max_denominator: i32) -> anyhow::Result<()> {
+        if self.graph_time_base()?.denominator() <= max_denominator {
+            return Ok(());
+        }
+
+        let time_base = self
+            .output
+            .frame_rate
+            .map(|frame_rate| frame_rate.invert())
+            .filter(|time_base| time_base.denominator() <= max_denominator)
+            .unwrap_or(ffmpeg::Rational::new(1, 1000));
+        self.output_time_base = Some(time_base);
+        Ok(())
+    }
+
+    fn graph_time_base(&mut self) -> anyhow::Result<ffmpeg::Rational> {
+        Ok(self.filter_graphs[0]
+            .get("out")
+            .ok_or(anyhow::anyhow!("Found missing output for a filter!"))?
+            .sink()
+            .time_base())
+    }
+
+    /// Sends the frame with the timestamp in the time base of the input stream to the
+    /// deinterlacer if there's one, and then to the graph of its segment.
+    pub(crate) fn send_frame(&mut self, frame: &ffmpeg::frame::Video) -> anyhow::Result<()> {
+        let Some(deinterlacer) = &mut self.deinterlacer else {
+            return self.route_frame(frame);
+        };
+
+        deinterlacer
+            .get("in")
+            .ok_or(anyhow::anyhow!("Found missing input of filter graph!"))?
+            .source()
+            .add(frame)?;
+        for frame in self.deinterlaced_frames()? {
+            self.route_frame(&frame)?;
+        }
+
+        Ok(())
+    }
+
+    /// Takes frames which came out of the deinterlacer.
+    fn deinterlaced_frames(&mut self) -> anyhow::Result<Vec<ffmpeg::frame::Video>> {
+        let mut frames = vec![];
+        let Some(deinterlacer) = &mut self.deinterlacer else {
+            return Ok(frames);
+        };
+
+        loop {
+            let mut frame = ffmpeg::frame::Video::empty();
+            if deinterlacer
+                .get("out")
+                .ok_or(anyhow::anyhow!("Found missing output for a filter!"))?
+                .sink()
+                .frame(&mut frame)
+                .is_err()
+            {
+                return Ok(frames);
+            }
+            frames.push(frame);
+        }
+    }
+
+    /// Sends the frame to the graph of its segment. Frames without the timestamp stay in the
+    /// segment of the previous one.
+    fn route_frame(&mut self, frame: &ffmpeg::frame::Video) -> anyhow::Result<()> {
+        if let Some(pts) = frame.pts() {
+            let seconds = pts as f64 * f64::from(self.time_base) - self.output.start_time;
+            let time = Duration::from_secs_f64(seconds.max(0.0));
+            self.current = self
+                .transform
+                .segment_at(time)
+                .map_or(0, |segment| segment + 1);
+        }
+
+        self.filter_graphs[self.current]
+            .get("in")
+            .ok_or(anyhow::anyhow!("Found missing input of filter graph!"))?
+            .source()
+            .add(frame)?;
+        Ok(())
+    }
+
+    /// Tells every graph that no frames are coming anymore, so the frames they hold come out.
+    pub(crate) fn send_eof(&mut self) -> anyhow::Result<()> {
+        if let Some(deinterlacer) = &mut self.deinterlacer {
+            deinterlacer
+                .get("in")
+                .ok_or(anyhow::anyhow!("Found missing input of filter graph!"))?
+                .source()
+                .flush()?;
+            for frame in self.deinterlaced_frames()? {
+                self.route_frame(&frame)?;
+            }
+        }
+
+        for filter_graph in &mut self.filter_graphs {
+            filter_graph
+                .get("in")
+                .ok_or(anyhow::anyhow!("Found missing input of filter graph!"))?
+                .source()
+                .flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Passes frames coming out of the filter to the processor with their original timestamps.
+    /// Encoders need them to increase, so a frame at the same time as the previous one follows it
+    /// by a tick. That happens when a coarser time base merges close timestamps of variable frame
+    /// rate recordings, and no frame is lost then. Frames going back in time or without a
+    /// timestamp are an error.
+    pub(crate) fn process_frames<F: FnMut(&mut ffmpeg::frame::Video) -> anyhow::Result<()>>(
+        &mut self,
+        mut processor: F,
+    ) -> anyhow::Result<()> {
+        let mut frame = ffmpeg::frame::Video::empty();
+
+        // Every frame comes out of its graph before the next one is sent, so the order is kept.
+        for filter_graph in &mut self.filter_graphs {
+            loop {
+                let mut output = filter_graph
+                    .get("out")
+                    .ok_or(anyhow::anyhow!("Found missing output for a filter!"))?;
+                let mut sink = output.sink();
+                if sink.frame(&mut frame).is_err() {
+                    break;
+                }
+
+                let mut pts = frame.pts();
+                if let Some(time_base) = self.output_time_base {
+                    pts = pts.map(|pts| pts.rescale(sink.time_base(), time_base));
+                    unsafe {
+                        let duration = &mut (*frame.as_mut_ptr()).duration;
+                        *duration = duration.rescale(sink.time_base(), time_base);
+                    }
+                }
+                let pts = pts.ok_or(anyhow::anyhow!(
+                    "The filter put out a frame without a timestamp"
+                ))?;
+                let output_pts = next_pts(pts, self.last_pts)?;
+                frame.set_pts(Some(output_pts));
+                self.last_pts = Some((pts, output_pts));
+
+                processor(&mut frame)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the timestamp the frame with the given one goes out with after the last frame, whose
+/// timestamps are given as `(pts, output_pts)`. Frames with merged timestamps are moved a tick
+/// after the previous one, along with the frames following them closer than that.
+fn next_pts(pts: i64, last: Option<(i64, i64)>) -> anyhow::Result<i64> {
+    match last {
+        None => Ok(pts),
+        Some((last_pts, _)) if pts < last_pts => {
+            anyhow::bail!("The timestamps of frames go back from {last_pts} to {pts}")
+        }
+        Some((last_pts, last_output_pts)) if pts == last_pts => Ok(last_output_pts + 1),
+        Some((_, last_output_pts)) => Ok(pts.max(last_output_pts + 1)),
+    }
+}
+
+/// Makes the graph applying the operations to frames of the decoder. The result is scaled to fit
+/// the canvas and padded with black if it's given, then converted into the pixel format. Returns
+/// the graph with the geometry of its output.
+fn create_graph(
+    decoder: &VideoDecoder,
+    time_base: ffmpeg::Rational,
+    input: Geometry,
+    operations: &[Operation],
+    canvas: Option<(u32, u32)>,
+    output_format: ffmpeg::format::Pixel,
+) -> anyhow::Result<(ffmpeg::filter::Graph, Geometry)> {
+    let mut filter_graph = ffmpeg::filter::Graph::new();
+    let filter_args = buffer_args(decoder, time_base, input);
+    filter_graph.add(&ffmpeg::filter::find("buffer").unwrap(), "in", &filter_args)?;
+    filter_graph.add(&ffmpeg::filter::find("buffersink").unwrap(), "out", "")?;
+
+    let pixel_format = decoder
+        .format()
+        .descriptor()
+        .map_or("yuv420p", |descriptor| descriptor.name());
+    let mut output = input;
+    let mut filters = vec![];
+    for operation in operations {
+        filters.push(operation.as_filter(&mut output, pixel_format)?);
+    }
+
+    // Frames are fitted by the shape they're shown in, so their pixels are made square first.
+    if let Some((width, height)) = canvas {
+        let square = square_pixels(&mut output);
+        filters.push(format!(
+            "{square}scale={width}:{height}:force_original_aspect_ratio=decrease:\
+             force_divisible_by=2,pad={width}:{height}:-1:-1:color=black"
+        ));
+        (output.width, output.height) = (width, height);
+    }
+
+    if output_format != decoder.format()
+        && let Some(descriptor) = output_format.descriptor()
+    {
+        filters.push(format!("format=pix_fmts={}", descriptor.name()));
+    }
+
+    filter_graph
+        .output("in", 0)?
+        .input("out", 0)?
+        .parse(&filters.join(","))?;
+    filter_graph.validate()?;
+
+    // The geometry is computed beforehand, but the graph has the final say.
+    let (width, height, sample_aspect_ratio) = sink_geometry(&mut filter_graph)?;
+    if (width, height) != (output.width, output.height) {
+        anyhow::bail!(
+            "The filters make {width}x{height} frames instead of {}x{}",
+            output.width,
+            output.height
+        );
+    }
+    // Filters like pad may round the aspect ratio of pixels differently.
+    if sample_aspect_ratio.numerator() > 0 && sample_aspect_ratio.denominator() > 0 {
+        output.sample_aspect_ratio = sample_aspect_ratio;
+    }
+
+    Ok((filter_graph, output))
+}
+
+/// Makes the graph deinterlacing frames of the decoder with the filter. Returns the graph with
+/// the time base of its output, which deinterlacers make finer.
+fn create_deinterlacer(
+    decoder: &VideoDecoder,
+    time_base: ffmpeg::Rational,
+    input: Geometry,
+    deinterlace: &str,
+) -> anyhow::Result<(ffmpeg::filter::Graph, ffmpeg::Rational)> {
+    let mut filter_graph = ffmpeg::filter::Graph::new();
+    let filter_args = buffer_args(decoder, time_base, input);
+
+    filter_graph.add(&ffmpeg::filter::find("buffer").unwrap(), "in", &filter_args)?;
+    filter_graph.add(&ffmpeg::filter::find("buffersink").unwrap(), "out", "")?;
+    filter_graph
+        .output("in", 0)?
+        .input("out", 0)?
+        .parse(deinterlace)?;
+    filter_graph.validate()?;
+
+    let time_base = filter_graph
+        .get("out")
+        .ok_or(anyhow::anyhow!("Found missing output for a filter!"))?
+        .sink()
+        .time_base();
+    Ok((filter_graph, time_base))
+}
+
+/// Returns the arguments of the buffer source taking frames of the decoder.
+fn buffer_args(decoder: &VideoDecoder, time_base: ffmpeg::Rational, input: Geometry) -> String {
+    let mut filter_args = format!(
+        "video_size={}x{}:pixel_aspect={}/{}",
+        input.width,
+        input.height,
+        input.sample_aspect_ratio.numerator(),
+        input.sample_aspect_ratio.denominator(),
+    );
+    if let Some(pix_fmt) = decoder.format().descriptor() {
+        filter_args = filter_args + ":pix_fmt=" + pix_fmt.name();
+    }
+
+    // Frames keep timestamps of the stream, so filters measuring time need its time base.
+    filter_args = filter_args
+        + ":time_base="
+        + &time_base.numerator().to_string()
+        + "/"
+        + &time_base.denominator().to_string();
+
+    if let Some(frame_rate) = input.frame_rate {
+        filter_args = filter_args
+            + ":frame_rate="
+            + &frame_rate.numerator().to_string()
+            + "/"
+            + &frame_rate.denominator().to_string();
+    }
+
+    if let Some(color_space) = decoder.color_space().name() {
+        filter_args = filter_args + ":colorspace=" + color_space;
+    }
+
+    if let Some(color_range) = decoder.color_range().name() {
+        filter_args = filter_args + ":range=" + color_range;
+    }
+
+    filter_args
+}
+
+fn sink_geometry(
+    filter_graph: &mut ffmpeg::filter::Graph,
+) -> anyhow::Result<(u32, u32, ffmpeg::Rational)> {
+    let sink = filter_graph
+        .get("out")
+        .ok_or(anyhow::anyhow!("Found missing output for a filter!"))?;
+
+    let (width, height, sample_aspect_ratio) = unsafe {
+        (
+            ffmpeg::ffi::av_buffersink_get_w(sink.as_ptr()),
+            ffmpeg::ffi::av_buffersink_get_h(sink.as_ptr()),
+            ffmpeg::ffi::av_buffersink_get_sample_aspect_ratio(sink.as_ptr()),
+        )
+    };
+    Ok((width as u32, height as u32, sample_aspect_ratio.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output_timestamps(timestamps: &[i64]) -> anyhow::Result<Vec<i64>> {
+        let mut last = None;
+        timestamps
+            .iter()
+            .map(|&pts| {
+                let output_pts = next_pts(pts, last)?;
+                last = Some((pts, output_pts));
+                Ok(output_pts)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn merged_timestamps_follow_by_a_tick() {
+        assert_eq!(output_timestamps(&[0, 1, 2, 5]).unwrap(), [0, 1, 2, 5]);
+        assert_eq!(
+            output_timestamps(&[0, 3, 3, 3, 4, 7]).unwrap(),
+            [0, 3, 4, 5, 6, 7]
+        );
+        assert_eq!(output_timestamps(&[10, 10]).unwrap(), [10, 11]);
+    }
+
+    #[test]
+    fn timestamps_going_back_are_an_error() {
+        assert!(output_timestamps(&[0, 5, 4]).is_err());
+        // Even when the previous frame was moved.
+        assert!(output_timestamps(&[0, 3, 3, 2]).is_err());
+    }
+}
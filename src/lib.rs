@@ -0,0 +1,154 @@
+//! Rotation of video files with FFmpeg.
+//!
+//! Video streams are either re-encoded with the rotation applied to pixels or stream copied with
+//! an updated display matrix, see [`Method`]. All other streams are stream copied or dropped, see
+//! [`TrackOptions`].
+//!
+//! ```no_run
+//! use video_rotator::{Method, Pipeline, Rotate};
+//!
+//! video_rotator::init()?;
+//!
+//! let mut pipeline = Pipeline::builder()
+//!     .input("input.mp4")
+//!     .output("output.mp4")
+//!     .rotate(Rotate::Deg90)
+//!     .method(Method::Reencode)
+//!     .build()?;
+//! pipeline.run(())?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+#![warn(missing_docs)]
+
+use ffmpeg_next as ffmpeg;
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+macro_rules! impl_from {
+    ($origin:ty => $dest:ident) => {
+        impl From<$origin> for $dest {
+            fn from(value: $origin) -> $dest {
+                $dest(value)
+            }
+        }
+    };
+
+    ($origin:ty => $dest:ident::$variant:ident) => {
+        impl From<$origin> for $dest {
+            fn from(value: $origin) -> $dest {
+                $dest::$variant(value)
+            }
+        }
+    };
+}
+
+mod cancel;
+mod codec;
+mod cover;
+mod destination;
+mod filter;
+mod pipeline;
+mod progress;
+mod rotate;
+mod scheduler;
+mod source;
+mod track;
+mod transform;
+mod verify;
+
+pub use cancel::{CancelHandle, Cancelled};
+pub use codec::{EncoderOptions, Quality, VideoCodec};
+pub use pipeline::{Pipeline, PipelineBuilder};
+pub use progress::{JobInfo, Progress, ProgressSink, StreamInfo, StreamKind, Throttle};
+pub use rotate::{Fill, Method, Rotate, Tilt};
+pub use scheduler::Scheduler;
+pub use track::{Track, TrackOptions, tracks};
+pub use transform::{Deinterlace, Operation, Scale, Segment, parse_size};
+
+/// Extensions of the containers the rotation is tested with.
+pub const SUPPORTED_EXTENSIONS: [&str; 2] = ["mp4", "mkv"];
+
+/// Whether the file has one of [`SUPPORTED_EXTENSIONS`], in any case.
+pub fn is_supported<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref().extension().is_some_and(|extension| {
+        SUPPORTED_EXTENSIONS
+            .iter()
+            .any(|supported| extension.eq_ignore_ascii_case(supported))
+    })
+}
+
+/// Initializes FFmpeg. It must be called once before any [`Pipeline`] is built.
+pub fn init() -> anyhow::Result<()> {
+    ffmpeg::init()?;
+    Ok(())
+}
+
+/// Makes a path in the output directory with the name of the input file, which doesn't
+/// overwrite any existing file. Fails if the input path has no file name.
+pub fn generate_unique_filename<Output: AsRef<Path>, Input: AsRef<Path>>(
+    output_dir: Output,
+    input_file: Input,
+) -> anyhow::Result<PathBuf> {
+    unique_filename(output_dir.as_ref(), input_file.as_ref(), |path| {
+        path.exists()
+    })
+}
+
+/// Makes paths for all input files like [`generate_unique_filename`], which don't clash with
+/// each other either. Outputs of queued jobs are created only when the jobs start, so inputs
+/// with the same name from different directories would get the same path otherwise.
+pub fn generate_unique_filenames<Output: AsRef<Path>, Input: AsRef<Path>>(
+    output_dir: Output,
+    input_files: &[Input],
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut reserved = HashSet::new();
+
+    input_files
+        .iter()
+        .map(|input_file| {
+            let path = unique_filename(output_dir.as_ref(), input_file.as_ref(), |path| {
+                path.exists() || reserved.contains(path)
+            })?;
+            reserved.insert(path.clone());
+            Ok(path)
+        })
+        .collect()
+}
+
+/// Adds a counter to the name of the input file until the path in the output directory isn't
+/// taken.
+fn unique_filename<F: Fn(&Path) -> bool>(
+    output_dir: &Path,
+    file_path: &Path,
+    is_taken: F,
+) -> anyhow::Result<PathBuf> {
+    let file_name = file_path
+        .file_stem()
+        .ok_or(anyhow::anyhow!("{} has no file name", file_path.display()))?;
+    let file_name = file_name.to_string_lossy();
+    // Names without the extension are kept as they are.
+    let file_extension = file_path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+
+    let mut count = 1;
+    let mut output_file_path = output_dir.join(format!("{file_name}{file_extension}"));
+
+    while is_taken(&output_file_path) {
+        output_file_path = output_dir.join(format!("{file_name}({count}){file_extension}"));
+
+        count += 1;
+    }
+
+    Ok(output_file_path)
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+pub(crate) struct StreamId(pub(crate) usize);
+
+impl_from!(usize => StreamId);
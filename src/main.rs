@@ -1,27 +1,34 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use ffmpeg_next as ffmpeg;
 use slint::{ComponentHandle, Model, ModelRc, ToSharedString, VecModel, Weak};
-
-use std::{
-    collections::HashMap,
-    path::{Path, PathBuf},
-    sync::{Arc, Mutex},
-    thread::JoinHandle,
-    time::Duration,
+use video_rotator::{
+    CancelHandle, Cancelled, Deinterlace, EncoderOptions, Fill, JobInfo, Method, Operation,
+    Pipeline, Progress, ProgressSink, Quality, Rotate, Scale, Scheduler, Segment, StreamInfo,
+    StreamKind, Throttle, Tilt, Track, TrackOptions, VideoCodec,
 };
 
+use std::{path::Path, rc::Rc, str::FromStr, sync::Arc, time::Duration};
+
 mod locale;
 
 slint::include_modules!();
 
-const SUPPORTED_EXTENSIONS: [&str; 2] = ["mp4", "mkv"];
+/// How often the progress of files is updated in the window.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
 
 fn main() -> anyhow::Result<()> {
-    ffmpeg::init()?;
+    video_rotator::init()?;
 
     let window = MainWindow::new()?;
-    let thread_pool: Arc<Mutex<Vec<JoinHandle<anyhow::Result<()>>>>> = Arc::new(Mutex::new(vec![]));
+
+    let codecs = Rc::new(VideoCodec::available());
+    let codec_infos: Vec<CodecInfo> = codecs.iter().map(CodecInfo::from).collect();
+    let codec_names: Vec<slint::SharedString> = codecs
+        .iter()
+        .map(|codec| codec.name().to_shared_string())
+        .collect();
+    window.set_codecs(ModelRc::new(VecModel::from(codec_infos)));
+    window.set_codec_names(ModelRc::new(VecModel::from(codec_names)));
 
     let empty_file_infos: VecModel<FileInfo> = VecModel::from(vec![]);
     let model = ModelRc::new(empty_file_infos);
@@ -54,34 +61,44 @@ fn main() -> anyhow::Result<()> {
                 return;
             }
 
-            let Some(file_extension) = file.extension() else {
-                return;
-            };
-
-            if !SUPPORTED_EXTENSIONS.contains(&&*file_extension.to_string_lossy()) {
+            if !video_rotator::is_supported(&file) {
                 return;
             }
 
-            let file_info = FileInfo {
-                name: file
-                    .file_name()
-                    .unwrap()
-                    .to_string_lossy()
-                    .to_shared_string(),
-                path: file.to_string_lossy().to_shared_string(),
-                progress: 0.0,
-            };
+            let name = file
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .to_shared_string();
+            let path = file.to_string_lossy().to_shared_string();
+            // Unreadable files fail when they're rotated, so they just have no tracks.
+            let tracks: Vec<TrackInfo> = video_rotator::tracks(&file)
+                .unwrap_or_default()
+                .iter()
+                .map(TrackInfo::from)
+                .collect();
 
             weak_window
                 .clone()
                 .upgrade_in_event_loop(|window| {
+                    let file_info = FileInfo {
+                        name,
+                        path,
+                        tracks: ModelRc::new(VecModel::from(tracks)),
+                        progress: 0.0,
+                        status: JobStatus::Queued,
+                        error: Default::default(),
+                        queue_position: 0,
+                        encoding: Default::default(),
+                    };
+
                     let file_infos_model = window.get_file_infos();
                     let file_infos = file_infos_model
                         .as_any()
                         .downcast_ref::<VecModel<FileInfo>>()
                         .unwrap();
 
-                    if !file_infos.iter().any(|fi| fi == file_info) {
+                    if !file_infos.iter().any(|fi| fi.path == file_info.path) {
                         file_infos.push(file_info);
                     }
                 })
@@ -100,14 +117,73 @@ fn main() -> anyhow::Result<()> {
                 .unwrap();
 
             file_infos.remove(index as usize);
+
+            let selected_file = window.get_selected_file();
+            if selected_file == index {
+                window.set_selected_file(-1);
+            } else if selected_file > index {
+                window.set_selected_file(selected_file - 1);
+            }
         });
     });
 
     let weak_window = window.as_weak();
-    let referenced_thread_pool = thread_pool.clone();
+    window.on_track_changed(
+        move |file_index, track_index, keep, is_default, is_forced, language| {
+            let window = weak_window.upgrade().unwrap();
+            let Some(file_info) = window.get_file_infos().row_data(file_index as usize) else {
+                return;
+            };
+            // Tracks are shared with the row of the file, so changing them is enough.
+            if let Some(mut track) = file_info.tracks.row_data(track_index as usize) {
+                track.keep = keep;
+                track.is_default = is_default;
+                track.is_forced = is_forced;
+                track.language = language;
+                file_info.tracks.set_row_data(track_index as usize, track);
+            }
+        },
+    );
+
+    let weak_window = window.as_weak();
+    let scheduler = Arc::new(Scheduler::<usize>::new(
+        Scheduler::<usize>::default_concurrency(),
+        move |file_index, result: anyhow::Result<()>| {
+            let (status, error) = match result {
+                Ok(()) => (JobStatus::Done, String::new()),
+                Err(err) if err.is::<Cancelled>() => (JobStatus::Cancelled, String::new()),
+                Err(err) => (JobStatus::Failed, format!("{err:#}")),
+            };
+
+            // idgaf
+            let _ = weak_window.upgrade_in_event_loop(move |window| {
+                update_file_info(&window, file_index, |file_info| {
+                    file_info.status = status;
+                    file_info.error = error.to_shared_string();
+                    file_info.progress = match status {
+                        JobStatus::Done => 1.0,
+                        _ => 0.0,
+                    };
+                });
+                update_queue_positions(&window);
+
+                let file_infos = window.get_file_infos();
+                let is_finished = file_infos
+                    .iter()
+                    .all(|info| !matches!(info.status, JobStatus::Queued | JobStatus::Running));
+                if is_finished && window.get_is_transcoding() {
+                    window.set_is_transcoding(false);
+                    window.set_message(summarize(&file_infos));
+                }
+            });
+        },
+    ));
+    window.set_concurrency(Scheduler::<usize>::default_concurrency() as i32);
+
+    let weak_window = window.as_weak();
+    let referenced_scheduler = scheduler.clone();
     window.on_rotate_videos(move || {
         let window = weak_window.upgrade().unwrap();
-        window.set_is_transcoding(true);
 
         let file_infos_model = window.get_file_infos();
         let file_infos = file_infos_model
@@ -115,655 +191,473 @@ fn main() -> anyhow::Result<()> {
             .downcast_ref::<VecModel<FileInfo>>()
             .unwrap();
 
-        let output_directory = window.get_output_directory();
-        let rotation_value = window.get_rotation_value();
-
-        for (file_index, file_info) in file_infos.iter().enumerate() {
-            let output_file_path =
-                generate_unique_filename(output_directory.path.as_str(), &file_info);
-
-            let mut guard = referenced_thread_pool.lock().unwrap();
-            guard.push(new_pipeline(
-                window.as_weak(),
-                file_info,
-                file_index,
-                output_file_path,
-                rotation_value,
-            ));
+        if file_infos.row_count() == 0 {
+            return;
         }
-    });
-
-    let window_weak = window.as_weak();
-    let _therad_checker = std::thread::spawn(move || {
-        loop {
-            let mut pool_guard = thread_pool.lock().unwrap();
 
-            let mut to_remove = vec![];
-            for (index, thread) in pool_guard.iter().enumerate() {
-                if thread.is_finished() {
-                    to_remove.push(index)
-                }
+        let settings = match job_settings(&window, &codecs) {
+            Ok(settings) => settings,
+            Err(err) => {
+                window.set_message(Message {
+                    text: format!("{err:#}").to_shared_string(),
+                    mtype: MessageType::Error,
+                });
+                return;
             }
-
-            for index in to_remove.into_iter().rev() {
-                let thread = pool_guard.remove(index);
-                thread.join().unwrap().unwrap();
+        };
+        // Names are reserved for the whole batch, since queued jobs create their files later.
+        let output_directory = window.get_output_directory();
+        let inputs: Vec<slint::SharedString> = file_infos.iter().map(|info| info.path).collect();
+        let output_files = match video_rotator::generate_unique_filenames(
+            output_directory.path.as_str(),
+            &inputs
+                .iter()
+                .map(|input| input.as_str())
+                .collect::<Vec<_>>(),
+        ) {
+            Ok(output_files) => output_files,
+            Err(err) => {
+                window.set_message(Message {
+                    text: format!("{err:#}").to_shared_string(),
+                    mtype: MessageType::Error,
+                });
+                return;
             }
+        };
+        window.set_is_transcoding(true);
 
-            if pool_guard.is_empty() {
-                window_weak
-                    .upgrade_in_event_loop(move |window| {
-                        let is_transcoding = window.get_is_transcoding();
-                        if is_transcoding {
-                            window.set_is_transcoding(false);
-
-                            window.set_message(Message {
-                                text: tr!("Videos are successfully rotated!").to_shared_string(),
-                                mtype: MessageType::Info,
-                            });
-                        }
-                    })
-                    .unwrap();
-            }
+        referenced_scheduler.set_concurrency(window.get_concurrency() as usize);
 
-            drop(pool_guard);
-            std::thread::sleep(Duration::from_millis(200));
+        for ((file_index, mut file_info), output_file_path) in
+            file_infos.iter().enumerate().zip(output_files)
+        {
+            file_info.progress = 0.0;
+            file_info.status = JobStatus::Queued;
+            file_info.error = Default::default();
+            file_info.encoding = Default::default();
+            file_infos.set_row_data(file_index, file_info.clone());
+
+            let window_ref = window.as_weak();
+            let settings = settings.clone();
+            let input = file_info.path.clone();
+            let tracks = track_options(&file_info.tracks);
+            referenced_scheduler.submit(file_index, move |cancel_handle| {
+                rotate_file(
+                    window_ref,
+                    &input,
+                    tracks,
+                    file_index,
+                    output_file_path,
+                    settings,
+                    cancel_handle,
+                )
+            });
         }
+        update_queue_positions(&window);
     });
 
-    Ok(window.run()?)
-}
-
-fn generate_unique_filename<Output: AsRef<Path>>(
-    output_dir: Output,
-    file_info: &FileInfo,
-) -> PathBuf {
-    let file_path = Path::new(file_info.path.as_str());
-    let file_name = file_path.file_stem().unwrap();
-    let file_extension = file_path.extension().unwrap();
-    let output_dir = output_dir.as_ref();
-
-    let mut count = 1;
-    let mut output_file_path = {
-        let mut output = output_dir.to_owned();
-        output.push(file_name);
-        output = output.with_extension(file_extension);
-        output
-    };
-
-    while output_file_path.exists() {
-        output_file_path = output_dir.to_owned();
-        let new_file_name =
-            file_name.to_string_lossy().into_owned() + "(" + &count.to_string() + ")";
-        output_file_path.push(new_file_name);
-        output_file_path = output_file_path.with_extension(file_extension);
+    // Cancelling waits for the threads of jobs, which may be stuck in FFmpeg for a while, so it
+    // runs aside from the event loop. Rows are updated once the jobs report they're cancelled.
+    let referenced_scheduler = scheduler.clone();
+    window.on_cancel_video(move |index| {
+        let scheduler = referenced_scheduler.clone();
+        std::thread::spawn(move || scheduler.cancel(&(index as usize)));
+    });
 
-        count += 1;
-    }
+    window.on_cancel_all(move || {
+        let scheduler = scheduler.clone();
+        std::thread::spawn(move || scheduler.cancel_all());
+    });
 
-    output_file_path
+    Ok(window.run()?)
 }
 
-fn new_pipeline<P: AsRef<Path> + Send + 'static>(
-    window_ref: Weak<MainWindow>,
-    file: FileInfo,
-    file_index: usize,
-    output_file_path: P,
-    rotation_value: RotationValue,
-) -> JoinHandle<anyhow::Result<()>> {
-    std::thread::spawn(move || {
-        let mut pipeline = Pipeline::init(file.path, output_file_path, rotation_value.into())?;
-        pipeline.write_header()?;
-        pipeline.configure()?;
-        pipeline.pump_packets(move |new_progress| {
-            window_ref.upgrade_in_event_loop(move |window| {
-                let file_infos = window.get_file_infos();
-                let mut file_info = file_infos.row_data(file_index).unwrap();
-                file_info.progress = new_progress as f32;
-                file_infos.set_row_data(file_index, file_info);
-            })?;
-            Ok(())
-        })?;
-        pipeline.write_trailer()?;
-
-        Ok(())
-    })
+/// Settings of the control pane, which are the same for all files.
+#[derive(Clone)]
+struct JobSettings {
+    rotate: Rotate,
+    tilt: Option<Tilt>,
+    operations: Vec<Operation>,
+    scale: Option<Scale>,
+    segments: Vec<Segment>,
+    deinterlace: Deinterlace,
+    method: Method,
+    encoder_options: EncoderOptions,
 }
 
-struct Pipeline {
-    source: Source,
-    destination: Destination,
-    progress: f64,
-}
+fn job_settings(window: &MainWindow, codecs: &[VideoCodec]) -> anyhow::Result<JobSettings> {
+    let rotation_value = window.get_rotation_value();
+    let method = match rotation_value {
+        RotationValue::Upright => Method::BakeIn,
+        _ => window.get_rotation_method().into(),
+    };
 
-impl Pipeline {
-    fn init<Input: AsRef<Path>, Output: AsRef<Path>>(
-        input: Input,
-        output: Output,
-        rotate: Rotate,
-    ) -> anyhow::Result<Self> {
-        let source = Source::load(input)?;
-        let destination = Destination::create(output, &source, &rotate)?;
-
-        Ok(Self {
-            source,
-            destination,
-            progress: 0.0,
-        })
+    let tilt = (window.get_tilt() != 0).then(|| Tilt {
+        degrees: window.get_tilt().into(),
+        fill: window.get_tilt_fill().into(),
+    });
+    if tilt.is_some() && method == Method::Metadata {
+        anyhow::bail!("{}", tr!("Tilting needs re-encoding"));
     }
 
-    fn write_header(&mut self) -> anyhow::Result<()> {
-        self.destination.write_header()?;
-        Ok(())
+    let deinterlace: Deinterlace = window.get_deinterlace().into();
+    if deinterlace == Deinterlace::Always && method == Method::Metadata {
+        anyhow::bail!("{}", tr!("Deinterlacing needs re-encoding"));
     }
 
-    fn configure(&mut self) -> anyhow::Result<()> {
-        self.destination.setup_time_bases(&self.source)?;
-        Ok(())
+    let segments: Vec<Segment> = parse_list(&window.get_segments())?;
+    if !segments.is_empty() && method == Method::Metadata {
+        anyhow::bail!("{}", tr!("Rotating segments needs re-encoding"));
     }
 
-    fn write_trailer(&mut self) -> anyhow::Result<()> {
-        self.destination.write_trailer()?;
-        Ok(())
+    let mut operations = vec![];
+    // The aspect ratio comes right after the rotation, so operations can change the result.
+    let aspect_ratio = window.get_aspect_ratio();
+    if let Some((width, height)) = aspect_ratio.split_once(':') {
+        let invalid = || {
+            anyhow::anyhow!(
+                "{}",
+                tr!("Invalid aspect ratio {text}").replace("{text}", &aspect_ratio)
+            )
+        };
+        operations.push(Operation::Aspect {
+            width: width.parse().map_err(|_| invalid())?,
+            height: height.parse().map_err(|_| invalid())?,
+            fill: window.get_aspect_fill().into(),
+            offset: None,
+        });
+    }
+    operations.extend(parse_list::<Operation>(&window.get_operations())?);
+    if !operations.is_empty() && method == Method::Metadata {
+        anyhow::bail!("{}", tr!("Operations need re-encoding"));
     }
 
-    fn pump_packets<F: FnMut(f64) -> anyhow::Result<()>>(
-        &mut self,
-        mut on_update_progress: F,
-    ) -> anyhow::Result<()> {
-        for (input_stream, mut packet) in self.source.input_ctx.packets() {
-            if let Some(current_time) = packet.pts() {
-                self.progress = current_time as f64 / input_stream.duration() as f64;
-                on_update_progress(self.progress)?;
-            }
-
-            let istream_index: StreamId = input_stream.index().into();
-
-            let in_time_base = self.source.time_bases[&istream_index];
-            let out_time_base = self.destination.time_bases[&istream_index];
-
-            match self.source.decoders.get_mut(&istream_index) {
-                Some(decoder) => {
-                    let encoder = self.destination.encoders.get_mut(&istream_index).ok_or(
-                        anyhow::anyhow!("Found missing encoder to corresponding decoder"),
-                    )?;
-                    let filter =
-                        self.destination
-                            .filters
-                            .get_mut(&istream_index)
-                            .ok_or(anyhow::anyhow!(
-                                "Found missing filter to corresponding decoder"
-                            ))?;
-
-                    packet.rescale_ts(input_stream.time_base(), in_time_base);
-
-                    let mut pipe = Pipe {
-                        output_ctx: &mut self.destination.output_ctx,
-                        decoder,
-                        filter,
-                        encoder,
-                        stream_id: istream_index,
-                        in_time_base,
-                        out_time_base,
-                    };
-                    pipe.decode_packet(&packet)?;
-                    pipe.apply_filter()?;
-                    pipe.encode_packets()?;
-                }
-                None => {
-                    // Do stream copy on non-video streams.
-                    packet.rescale_ts(in_time_base, out_time_base);
-                    packet.set_position(-1);
-                    packet.set_stream(istream_index.0);
-                    packet
-                        .write_interleaved(&mut self.destination.output_ctx)
-                        .unwrap();
-                }
-            }
-        }
-
-        for (id, decoder) in &mut self.source.decoders {
-            let in_time_base = self.source.time_bases[id];
-            let out_time_base = self.destination.time_bases[id];
-
-            let encoder = self
-                .destination
-                .encoders
-                .get_mut(id)
-                .ok_or(anyhow::anyhow!(
-                    "Found missing encoder to corresponding decoder"
-                ))?;
-            let filter = self.destination.filters.get_mut(id).ok_or(anyhow::anyhow!(
-                "Found missing filter to corresponding decoder"
-            ))?;
-
-            let mut pipe = Pipe {
-                output_ctx: &mut self.destination.output_ctx,
-                decoder,
-                filter,
-                encoder,
-                stream_id: *id,
-                in_time_base,
-                out_time_base,
-            };
+    let scale = match window.get_scale().as_str() {
+        "" => None,
+        scale => Some(scale.parse::<Scale>()?),
+    };
+    if scale.is_some() && method == Method::Metadata {
+        anyhow::bail!("{}", tr!("Scaling needs re-encoding"));
+    }
 
-            pipe.send_eof_decoder()?;
-            pipe.apply_filter()?;
-            pipe.encode_packets()?;
+    let settings = window.get_encoder_settings();
+    let codec = *codecs
+        .get(settings.codec as usize)
+        .ok_or(anyhow::anyhow!("No video encoders are available"))?;
+    let non_empty = |value: slint::SharedString| {
+        let value = value.trim();
+        (!value.is_empty()).then(|| value.to_owned())
+    };
 
-            pipe.send_eof_encoder()?;
-            pipe.apply_filter()?;
-            pipe.encode_packets()?;
-        }
+    let quality = match (settings.quality_mode, codec.max_crf()) {
+        (QualityMode::MatchSource, _) => Quality::MatchSource,
+        (QualityMode::Lossless, _) => Quality::Lossless,
+        (QualityMode::Crf, Some(_)) => Quality::Crf(settings.crf as u8),
+        _ => Quality::Bitrate(settings.bitrate as usize * 1000),
+    };
 
-        Ok(())
+    let encoder_options = EncoderOptions {
+        codec,
+        preset: codec
+            .supports_presets()
+            .then(|| settings.preset.to_string()),
+        quality,
+        profile: non_empty(settings.profile).filter(|_| codec.supports_profiles()),
+        level: non_empty(settings.level).filter(|_| codec.supports_profiles()),
+        two_pass: settings.two_pass
+            && codec.supports_two_pass()
+            && matches!(quality, Quality::Bitrate(_) | Quality::MatchSource),
+        max_compatibility: settings.max_compatibility,
+    };
+    // Only re-encoding uses the encoder, the metadata rotation shouldn't fail because of it.
+    if method != Method::Metadata {
+        encoder_options.validate()?;
     }
-}
 
-struct Pipe<'a> {
-    output_ctx: &'a mut ffmpeg::format::context::Output,
-    decoder: &'a mut VideoDecoder,
-    filter: &'a mut Filter,
-    encoder: &'a mut VideoEncoder,
-    stream_id: StreamId,
-    in_time_base: ffmpeg::Rational,
-    out_time_base: ffmpeg::Rational,
+    Ok(JobSettings {
+        rotate: rotation_value.into(),
+        tilt,
+        operations,
+        scale,
+        segments,
+        deinterlace,
+        method,
+        encoder_options,
+    })
 }
 
-impl<'a> Pipe<'a> {
-    fn send_eof_decoder(&mut self) -> anyhow::Result<()> {
-        self.decoder.send_eof()?;
-        self.decoder.process_frames(|frame| {
-            let timestamp = frame.timestamp();
-            frame.set_pts(timestamp);
+/// Parses the list separated by commas or semicolons, skipping empty items.
+fn parse_list<T: FromStr<Err = anyhow::Error>>(list: &str) -> anyhow::Result<Vec<T>> {
+    list.split([',', ';'])
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::parse)
+        .collect()
+}
 
-            self.filter.send_frame(frame)
+/// Returns the options of every track of the file as they're chosen in the track list.
+fn track_options(tracks: &ModelRc<TrackInfo>) -> Vec<(usize, TrackOptions)> {
+    tracks
+        .iter()
+        .map(|track| {
+            let options = TrackOptions {
+                keep: track.keep,
+                default: Some(track.is_default),
+                forced: Some(track.is_forced),
+                language: Some(track.language.to_string()),
+            };
+            (track.index as usize, options)
         })
-    }
+        .collect()
+}
 
-    fn send_eof_encoder(&mut self) -> Result<(), ffmpeg::Error> {
-        self.encoder.send_eof()
+fn rotate_file<P: AsRef<Path>>(
+    window_ref: Weak<MainWindow>,
+    input: &str,
+    tracks: Vec<(usize, TrackOptions)>,
+    file_index: usize,
+    output_file_path: P,
+    settings: JobSettings,
+    cancel_handle: CancelHandle,
+) -> anyhow::Result<()> {
+    let mut builder = Pipeline::builder()
+        .input(input)
+        .output(output_file_path)
+        .rotate(settings.rotate)
+        .deinterlace(settings.deinterlace)
+        .method(settings.method)
+        .encoder_options(settings.encoder_options)
+        .cancel_handle(cancel_handle);
+    if let Some(tilt) = settings.tilt {
+        builder = builder.tilt(tilt);
     }
-
-    fn decode_packet(&mut self, packet: &ffmpeg::Packet) -> anyhow::Result<()> {
-        self.decoder.send_packet(packet)?;
-        self.decoder.process_frames(|frame| {
-            let timestamp = frame.timestamp();
-            frame.set_pts(timestamp);
-
-            self.filter.send_frame(frame)
-        })
+    for operation in settings.operations {
+        builder = builder.operation(operation);
     }
-
-    fn apply_filter(&mut self) -> anyhow::Result<()> {
-        self.filter.process_frames(|frame| {
-            self.encoder.send_frame(frame)?;
-            Ok(())
-        })
+    if let Some(scale) = settings.scale {
+        builder = builder.scale(scale);
     }
-
-    fn encode_packets(&mut self) -> Result<(), ffmpeg::Error> {
-        self.encoder.process_packets(|packet| {
-            packet.set_stream(self.stream_id.0);
-            packet.rescale_ts(self.in_time_base, self.out_time_base);
-            packet.write_interleaved(self.output_ctx)
-        })
+    for segment in settings.segments {
+        builder = builder.segment(segment);
+    }
+    for (index, options) in tracks {
+        builder = builder.track(index, options);
     }
-}
 
-struct Source {
-    _input_file: PathBuf,
-    input_ctx: ffmpeg::format::context::Input,
-    decoders: HashMap<StreamId, VideoDecoder>,
-    time_bases: HashMap<StreamId, ffmpeg::Rational>,
+    let mut pipeline = builder.build()?;
+    pipeline.run(Throttle::new(
+        FileProgress {
+            window_ref,
+            file_index,
+            qualities: vec![],
+        },
+        PROGRESS_INTERVAL,
+    ))
 }
 
-impl Source {
-    fn load<Input: AsRef<Path>>(input: Input) -> anyhow::Result<Self> {
-        let input_ctx = ffmpeg::format::input(input.as_ref())?;
-
-        ffmpeg::format::context::input::dump(&input_ctx, 0, input.as_ref().to_str());
-
-        let mut decoders = HashMap::new();
-        let mut time_bases = HashMap::new();
-        for (index, stream) in input_ctx.streams().enumerate() {
-            let media_type = stream.parameters().medium();
-            time_bases.insert(index.into(), stream.time_base());
-
-            if let ffmpeg::media::Type::Video = media_type {
-                let decoder_context = ffmpeg::codec::Context::from_parameters(stream.parameters())?;
-                let mut decoder = decoder_context.decoder().video()?;
-                decoder.set_parameters(stream.parameters())?;
-
-                decoders.insert(index.into(), decoder.into());
-            }
-        }
-
-        Ok(Self {
-            _input_file: input.as_ref().to_owned(),
-            input_ctx,
-            decoders,
-            time_bases,
-        })
+fn update_file_info<F: FnOnce(&mut FileInfo)>(window: &MainWindow, file_index: usize, update: F) {
+    let file_infos = window.get_file_infos();
+    if let Some(mut file_info) = file_infos.row_data(file_index) {
+        update(&mut file_info);
+        file_infos.set_row_data(file_index, file_info);
     }
 }
 
-struct Destination {
-    output_file: PathBuf,
-    output_ctx: ffmpeg::format::context::Output,
-    filters: HashMap<StreamId, Filter>,
-    encoders: HashMap<StreamId, VideoEncoder>,
-    time_bases: HashMap<StreamId, ffmpeg::Rational>,
-}
+/// Numbers queued files in the order they are going to be rotated.
+fn update_queue_positions(window: &MainWindow) {
+    let file_infos = window.get_file_infos();
 
-impl Destination {
-    fn create<Output: AsRef<Path>>(
-        output: Output,
-        source: &Source,
-        rotate: &Rotate,
-    ) -> anyhow::Result<Self> {
-        let mut output_ctx = ffmpeg::format::output(output.as_ref())?;
-        output_ctx.set_metadata(source.input_ctx.metadata().to_owned());
-
-        let mut filters = HashMap::new();
-        let mut encoders = HashMap::new();
-
-        for (index, input_stream) in source.input_ctx.streams().enumerate() {
-            if let Some(decoder) = source.decoders.get(&index.into()) {
-                let encoder = VideoEncoder::create_from_decoder(decoder, &input_stream, rotate)?;
-                let codec = encoder.codec().ok_or(anyhow::anyhow!(
-                    "Unknown codec. The encoder was wrongly configured."
-                ))?;
-                let mut output_stream = output_ctx.add_stream(codec)?;
-                output_stream.set_parameters(&encoder);
-
-                filters.insert(index.into(), Filter::create(decoder, rotate)?);
-                encoders.insert(index.into(), encoder);
+    let mut position = 0;
+    for file_index in 0..file_infos.row_count() {
+        update_file_info(window, file_index, |file_info| {
+            if file_info.status == JobStatus::Queued {
+                position += 1;
+                file_info.queue_position = position;
             } else {
-                // Set up for stream copy for non-video stream.
-                let mut output_stream =
-                    output_ctx.add_stream(ffmpeg::encoder::find(ffmpeg::codec::Id::None))?;
-                output_stream.set_parameters(input_stream.parameters());
-                // We need to set codec_tag to 0 lest we run into incompatible codec tag
-                // issues when muxing into a different container format. Unfortunately
-                // there's no high level API to do this (yet).
-                unsafe {
-                    (*output_stream.parameters().as_mut_ptr()).codec_tag = 0;
-                }
+                file_info.queue_position = 0;
             }
-        }
-
-        Ok(Self {
-            output_file: output.as_ref().to_owned(),
-            output_ctx,
-            filters,
-            encoders,
-            // INFO: it's unknown until begin of writing into a file
-            time_bases: HashMap::new(),
-        })
+        });
     }
+}
 
-    fn write_header(&mut self) -> anyhow::Result<()> {
-        ffmpeg::format::context::output::dump(&self.output_ctx, 0, self.output_file.to_str());
-        self.output_ctx.write_header()?;
-        Ok(())
-    }
+/// Makes the message about results of all files.
+fn summarize(file_infos: &ModelRc<FileInfo>) -> Message {
+    let count = |status: JobStatus| {
+        file_infos
+            .iter()
+            .filter(|info| info.status == status)
+            .count()
+    };
 
-    fn write_trailer(&mut self) -> anyhow::Result<()> {
-        self.output_ctx.write_trailer()?;
-        Ok(())
-    }
+    let total = file_infos.row_count();
+    let done = count(JobStatus::Done);
+    let failed = count(JobStatus::Failed);
 
-    fn setup_time_bases(&mut self, source: &Source) -> anyhow::Result<()> {
-        for (index, _) in source.input_ctx.streams().enumerate() {
-            let output_stream = self
-                .output_ctx
-                .stream(index)
-                .ok_or(anyhow::anyhow!("Found missing stream in destination."))?;
-            self.time_bases
-                .insert(index.into(), output_stream.time_base());
-        }
+    let text = if done == total {
+        tr!("Videos are successfully rotated!").to_owned()
+    } else {
+        tr!("Rotated {done} of {total} videos")
+            .replace("{done}", &done.to_string())
+            .replace("{total}", &total.to_string())
+    };
 
-        Ok(())
+    Message {
+        text: text.to_shared_string(),
+        mtype: if failed > 0 {
+            MessageType::Error
+        } else {
+            MessageType::Info
+        },
     }
 }
 
-struct Filter {
-    filter_graph: ffmpeg::filter::Graph,
+/// Shows the progress of the pipeline on the tile of its file.
+struct FileProgress {
+    window_ref: Weak<MainWindow>,
+    file_index: usize,
+    /// Qualities chosen for re-encoded streams.
+    qualities: Vec<String>,
 }
 
-impl Filter {
-    fn create(decoder: &VideoDecoder, rotate: &Rotate) -> Result<Self, ffmpeg::Error> {
-        let mut filter_graph = ffmpeg::filter::Graph::new();
-
-        let mut filter_args = format!(
-            "video_size={}x{}:pixel_aspect=1/1",
-            decoder.width(),
-            decoder.height(),
-        );
-        if let Some(pix_fmt) = decoder.format().descriptor() {
-            filter_args = filter_args + ":pix_fmt=" + pix_fmt.name();
-        }
-
-        if let Some(frame_rate) = decoder.frame_rate() {
-            filter_args = filter_args
-                + ":time_base="
-                + &frame_rate.denominator().to_string()
-                + "/"
-                + &frame_rate.numerator().to_string();
-        }
-
-        if let Some(color_space) = decoder.color_space().name() {
-            filter_args = filter_args + ":colorspace=" + color_space;
-        }
-
-        if let Some(color_range) = decoder.color_range().name() {
-            filter_args = filter_args + ":range=" + color_range;
-        }
+impl FileProgress {
+    fn set_progress(&self, new_progress: f64) {
+        let file_index = self.file_index;
 
-        dbg!(&filter_args);
-
-        filter_graph.add(&ffmpeg::filter::find("buffer").unwrap(), "in", &filter_args)?;
-        filter_graph.add(&ffmpeg::filter::find("buffersink").unwrap(), "out", "")?;
+        // idgaf
+        let _ = self.window_ref.upgrade_in_event_loop(move |window| {
+            update_file_info(&window, file_index, |file_info| {
+                file_info.progress = new_progress as f32;
+            });
+        });
+    }
+}
 
-        filter_graph
-            .output("in", 0)?
-            .input("out", 0)?
-            .parse(rotate.as_filter())?;
-        filter_graph.validate()?;
+impl ProgressSink for FileProgress {
+    fn started(&mut self, _job: &JobInfo) {
+        let file_index = self.file_index;
 
-        Ok(Self { filter_graph })
+        // idgaf
+        let _ = self.window_ref.upgrade_in_event_loop(move |window| {
+            update_file_info(&window, file_index, |file_info| {
+                file_info.status = JobStatus::Running;
+            });
+            update_queue_positions(&window);
+        });
     }
 
-    fn send_frame(&mut self, frame: &ffmpeg::frame::Video) -> anyhow::Result<()> {
-        self.filter_graph
-            .get("in")
-            .ok_or(anyhow::anyhow!("Found missing input of filter graph!"))?
-            .source()
-            .add(frame)?;
-        Ok(())
-    }
+    fn stream_info(&mut self, stream: &StreamInfo) {
+        let Some(quality) = stream.quality else {
+            return;
+        };
+        self.qualities.push(quality.to_string());
 
-    fn process_frames<F: FnMut(&mut ffmpeg::frame::Video) -> anyhow::Result<()>>(
-        &mut self,
-        mut processor: F,
-    ) -> anyhow::Result<()> {
-        let mut frame = ffmpeg::frame::Video::empty();
-
-        while self
-            .filter_graph
-            .get("out")
-            .ok_or(anyhow::anyhow!("Found missing output for a filter!"))?
-            .sink()
-            .frame(&mut frame)
-            .is_ok()
-        {
-            processor(&mut frame)?;
-        }
+        let file_index = self.file_index;
+        let encoding = self.qualities.join(", ");
 
-        Ok(())
+        // idgaf
+        let _ = self.window_ref.upgrade_in_event_loop(move |window| {
+            update_file_info(&window, file_index, |file_info| {
+                file_info.encoding = encoding.to_shared_string();
+            });
+        });
     }
-}
 
-#[allow(unused)]
-enum Rotate {
-    Deg0,
-    Deg90,
-    Deg180,
-    Deg270,
-}
-
-impl Rotate {
-    fn as_filter(&self) -> &str {
-        match self {
-            Rotate::Deg0 => "null",
-            Rotate::Deg90 => "transpose=1",
-            Rotate::Deg180 => "transpose=2,transpose=2",
-            Rotate::Deg270 => "transpose=2",
-        }
+    fn progress(&mut self, progress: &Progress) {
+        self.set_progress(progress.fraction);
     }
 
-    fn is_axis_flips(&self) -> bool {
-        match self {
-            Rotate::Deg0 | Rotate::Deg180 => false,
-            Rotate::Deg90 | Rotate::Deg270 => true,
-        }
+    fn finished(&mut self, progress: &Progress) {
+        self.set_progress(progress.fraction);
     }
 }
 
 impl From<RotationValue> for Rotate {
     fn from(value: RotationValue) -> Self {
         match value {
-            RotationValue::NoRotation => Rotate::Deg0,
+            RotationValue::NoRotation | RotationValue::Upright => Rotate::Deg0,
             RotationValue::Deg90 => Rotate::Deg90,
             RotationValue::Deg180 => Rotate::Deg180,
             RotationValue::Deg270 => Rotate::Deg270,
+            RotationValue::Hflip => Rotate::HFlip,
+            RotationValue::Vflip => Rotate::VFlip,
+            RotationValue::Transpose => Rotate::Transpose,
+            RotationValue::Transverse => Rotate::Transverse,
         }
     }
 }
 
-macro_rules! impl_from {
-    ($origin:ty => $dest:ident) => {
-        impl From<$origin> for $dest {
-            fn from(value: $origin) -> $dest {
-                $dest(value)
-            }
-        }
-    };
-
-    ($origin:ty => $dest:ident::$variant:ident) => {
-        impl From<$origin> for $dest {
-            fn from(value: $origin) -> $dest {
-                $dest::$variant(value)
-            }
+impl From<&VideoCodec> for CodecInfo {
+    fn from(codec: &VideoCodec) -> Self {
+        CodecInfo {
+            name: codec.name().to_shared_string(),
+            has_presets: codec.supports_presets(),
+            has_profiles: codec.supports_profiles(),
+            has_two_pass: codec.supports_two_pass(),
+            has_lossless: codec.supports_lossless(),
+            max_crf: codec.max_crf().unwrap_or_default().into(),
+            default_crf: codec.default_crf().unwrap_or_default().into(),
+            lossless: codec.is_lossless(),
         }
-    };
-}
-
-#[derive(PartialEq, Eq, Hash, Clone, Copy)]
-struct StreamId(usize);
-
-impl_from!(usize => StreamId);
-
-struct VideoDecoder(ffmpeg::codec::decoder::Video);
-
-impl VideoDecoder {
-    fn process_frames<F: FnMut(&mut ffmpeg::frame::Video) -> anyhow::Result<()>>(
-        &mut self,
-        mut processor: F,
-    ) -> anyhow::Result<()> {
-        let mut frame = ffmpeg::frame::Video::empty();
-        while self.0.receive_frame(&mut frame).is_ok() {
-            processor(&mut frame)?;
-        }
-
-        Ok(())
     }
 }
 
-impl_from!(ffmpeg::codec::decoder::Video => VideoDecoder);
-
-impl std::ops::Deref for VideoDecoder {
-    type Target = ffmpeg::codec::decoder::Video;
-
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
-
-impl std::ops::DerefMut for VideoDecoder {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
-    }
-}
-
-struct VideoEncoder(ffmpeg::codec::encoder::Video);
-
-impl VideoEncoder {
-    fn create_from_decoder(
-        decoder: &VideoDecoder,
-        corresponding_stream: &ffmpeg::Stream,
-        rotate: &Rotate,
-    ) -> anyhow::Result<Self> {
-        let video = &decoder.0;
-
-        // TODO: maybe need to handle different codecs.
-        let output_codec = ffmpeg::encoder::find(ffmpeg::codec::Id::H264)
-            .ok_or(anyhow::anyhow!("Cannot make encoder from given codec"))?;
-
-        let encoder_context = ffmpeg::codec::Context::new_with_codec(output_codec);
-        let mut encoder = encoder_context.encoder().video()?;
-
-        let (mut width, mut height) = (video.width(), video.height());
-
-        if rotate.is_axis_flips() {
-            (width, height) = (height, width);
+impl From<&Track> for TrackInfo {
+    fn from(track: &Track) -> Self {
+        let kind = match track.kind {
+            _ if track.attached_picture => tr!("Cover art"),
+            StreamKind::Video => tr!("Video"),
+            StreamKind::Audio => tr!("Audio"),
+            StreamKind::Subtitle => tr!("Subtitles"),
+            StreamKind::Data => tr!("Data"),
+            StreamKind::Attachment => tr!("Attachment"),
+            StreamKind::Unknown => tr!("Unknown"),
+        };
+        let mut description = format!("{kind} #{}: {}", track.index, track.codec);
+        if let Some(title) = &track.title {
+            description += &format!(" ({title})");
         }
 
-        encoder.set_width(width);
-        encoder.set_height(height);
-        encoder.set_format(video.format());
-        encoder.set_frame_rate(video.frame_rate());
-        encoder.set_time_base(corresponding_stream.time_base());
-
-        let mut options = ffmpeg::Dictionary::new();
-        options.set("preset", "medium");
-        options.set("crf", "23");
-
-        Ok(encoder.open_with(options)?.into())
-    }
-
-    fn process_packets<F: FnMut(&mut ffmpeg::Packet) -> Result<(), ffmpeg::Error>>(
-        &mut self,
-        mut processor: F,
-    ) -> Result<(), ffmpeg::Error> {
-        let mut packet = ffmpeg::Packet::empty();
-        while self.0.receive_packet(&mut packet).is_ok() {
-            processor(&mut packet)?;
+        TrackInfo {
+            index: track.index as i32,
+            description: description.to_shared_string(),
+            keep: true,
+            is_default: track.default,
+            is_forced: track.forced,
+            language: track
+                .language
+                .clone()
+                .unwrap_or_default()
+                .to_shared_string(),
         }
-
-        Ok(())
     }
 }
 
-impl_from!(ffmpeg::codec::encoder::Video => VideoEncoder);
-
-impl std::ops::Deref for VideoEncoder {
-    type Target = ffmpeg::codec::encoder::Video;
-
-    fn deref(&self) -> &Self::Target {
-        &self.0
+impl From<FillMode> for Fill {
+    fn from(value: FillMode) -> Self {
+        match value {
+            FillMode::Crop => Fill::Crop,
+            FillMode::Black => Fill::Color([0, 0, 0]),
+            FillMode::Blur => Fill::Blur,
+        }
     }
 }
 
-impl std::ops::DerefMut for VideoEncoder {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+impl From<DeinterlaceMode> for Deinterlace {
+    fn from(value: DeinterlaceMode) -> Self {
+        match value {
+            DeinterlaceMode::Auto => Deinterlace::Auto,
+            DeinterlaceMode::Detect => Deinterlace::Detect,
+            DeinterlaceMode::Always => Deinterlace::Always,
+            DeinterlaceMode::Never => Deinterlace::Never,
+        }
     }
 }
 
-impl From<&VideoEncoder> for ffmpeg::codec::Parameters {
-    fn from(value: &VideoEncoder) -> Self {
-        (&value.0).into()
+impl From<RotationMethod> for Method {
+    fn from(value: RotationMethod) -> Self {
+        match value {
+            RotationMethod::Reencode => Method::Reencode,
+            RotationMethod::Metadata => Method::Metadata,
+        }
     }
 }
@@ -0,0 +1,712 @@
+use ffmpeg_next as ffmpeg;
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    StreamId,
+    cancel::{CancelHandle, Cancelled},
+    codec::{EncoderOptions, Pass, Quality, VideoDecoder, VideoEncoder},
+    cover::is_attached_picture,
+    destination::Destination,
+    filter::Filter,
+    progress::{JobInfo, ProgressSink, Stats, StreamInfo},
+    rotate::{Method, Rotate, Tilt},
+    source::{self, Source},
+    track::TrackOptions,
+    transform::{Deinterlace, Operation, Scale, Segment, Transform},
+    verify,
+};
+
+/// Rotation of a single video file.
+///
+/// It's made with [`Pipeline::builder`] and driven either with [`Pipeline::run`] or step by step
+/// with [`Pipeline::write_header`], [`Pipeline::configure`], [`Pipeline::pump_packets`] and
+/// [`Pipeline::write_trailer`] in this order. Two-pass encoding is done only by
+/// [`Pipeline::run`], the steps make just the first pass.
+pub struct Pipeline {
+    source: Source,
+    destination: Destination,
+    transform: Transform,
+    method: Method,
+    encoder_options: EncoderOptions,
+    pass: Pass,
+    verify: bool,
+    stats: Stats,
+    cancel_handle: CancelHandle,
+}
+
+impl Pipeline {
+    /// Starts configuring a pipeline, see [`PipelineBuilder`].
+    pub fn builder() -> PipelineBuilder {
+        PipelineBuilder::default()
+    }
+
+    fn init(input: PathBuf, output: PathBuf, builder: PipelineBuilder) -> anyhow::Result<Self> {
+        let PipelineBuilder {
+            transform,
+            method,
+            encoder_options,
+            skip_verification,
+            cancel_handle,
+            ..
+        } = builder;
+
+        transform.validate()?;
+        let source = Source::load(input, &method, &transform)?;
+        // Loading may take a while with the detection of interlacing, and the output file
+        // shouldn't be created for a cancelled job at all.
+        if cancel_handle.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+
+        // Two passes are needed only if some stream ends up with the bitrate.
+        let two_pass = encoder_options.two_pass
+            && source.properties.values().any(|properties| {
+                matches!(
+                    encoder_options.resolve(properties).quality,
+                    Quality::Bitrate(_)
+                )
+            });
+        let pass = if two_pass { Pass::First } else { Pass::Single };
+
+        let destination =
+            Destination::create(output, &source, &transform, &method, &encoder_options, pass)?;
+        let verify =
+            !skip_verification && encoder_options.is_lossless() && !source.decoders.is_empty();
+
+        let mut pipeline = Self {
+            source,
+            destination,
+            transform,
+            method,
+            encoder_options,
+            pass,
+            verify,
+            stats: Stats::new(1),
+            cancel_handle,
+        };
+        pipeline.stats = Stats::new(pipeline.passes());
+
+        Ok(pipeline)
+    }
+
+    /// Returns a handle cancelling this pipeline.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel_handle.clone()
+    }
+
+    /// Does all steps of the rotation at once, reporting every event to the sink.
+    ///
+    /// The unfinished output file is removed when the rotation fails or is cancelled, so the
+    /// pipeline can't be used anymore after an error.
+    pub fn run<S: ProgressSink>(&mut self, mut sink: S) -> anyhow::Result<()> {
+        self.stats = Stats::new(self.passes());
+        sink.started(&self.job_info());
+        for stream in self.stream_infos() {
+            sink.stream_info(&stream);
+        }
+
+        let result = self.rotate(&mut sink);
+        if self.pass != Pass::Single {
+            self.destination.remove_stats_files();
+        }
+
+        match &result {
+            Ok(()) => {
+                self.stats.fraction = 1.0;
+                sink.finished(&self.stats.progress());
+            }
+            Err(err) => {
+                // The original error is more important than the failed cleanup.
+                let _ = self.destination.discard();
+                sink.failed(err);
+            }
+        }
+
+        result
+    }
+
+    fn rotate<S: ProgressSink>(&mut self, sink: &mut S) -> anyhow::Result<()> {
+        loop {
+            self.write_header()?;
+            self.configure()?;
+            self.pump_packets(sink)?;
+            self.write_trailer()?;
+
+            if !self.next_pass()? {
+                break;
+            }
+        }
+
+        if self.verify {
+            self.stats.next_pass();
+            verify::verify(
+                &self.source.input_file,
+                &self.destination.output_file,
+                &self.transform,
+                &self.method,
+                &self.cancel_handle,
+                |fraction| {
+                    self.stats.fraction = fraction;
+                    sink.progress(&self.stats.progress());
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the number of times the video is processed: one or two encoding passes and the
+    /// verification of lossless outputs.
+    fn passes(&self) -> u32 {
+        let encoding_passes = match self.pass {
+            Pass::Single => 1,
+            Pass::First | Pass::Second => 2,
+        };
+
+        encoding_passes + u32::from(self.verify)
+    }
+
+    /// Reopens the files for the second pass after the first one. Returns `false` if all passes
+    /// are done.
+    fn next_pass(&mut self) -> anyhow::Result<bool> {
+        if self.pass != Pass::First {
+            return Ok(false);
+        }
+
+        self.pass = Pass::Second;
+        self.source = Source::load(
+            self.source.input_file.clone(),
+            &self.method,
+            &self.transform,
+        )?;
+        self.destination = Destination::create(
+            self.destination.output_file.clone(),
+            &self.source,
+            &self.transform,
+            &self.method,
+            &self.encoder_options,
+            self.pass,
+        )?;
+        self.stats.next_pass();
+
+        Ok(true)
+    }
+
+    fn job_info(&self) -> JobInfo<'_> {
+        let duration = self.source.input_ctx.duration();
+
+        JobInfo {
+            input: &self.source.input_file,
+            output: &self.destination.output_file,
+            duration: (duration > 0).then(|| {
+                Duration::from_secs_f64(duration as f64 * f64::from(ffmpeg::rescale::TIME_BASE))
+            }),
+        }
+    }
+
+    fn stream_infos(&self) -> Vec<StreamInfo> {
+        self.source
+            .input_ctx
+            .streams()
+            .map(|stream| {
+                let parameters = stream.parameters();
+                let id: StreamId = stream.index().into();
+                StreamInfo {
+                    index: stream.index(),
+                    kind: parameters.medium().into(),
+                    codec: parameters.id().name().to_owned(),
+                    reencoded: self.source.decoders.contains_key(&id),
+                    dropped: !self.destination.output_streams.contains_key(&id),
+                    quality: self.destination.qualities.get(&id).copied(),
+                }
+            })
+            .collect()
+    }
+
+    /// Writes the header of the output file.
+    pub fn write_header(&mut self) -> anyhow::Result<()> {
+        self.destination.write_header()?;
+        Ok(())
+    }
+
+    /// Takes the time bases of output streams, which are chosen by the muxer when the header is
+    /// written.
+    pub fn configure(&mut self) -> anyhow::Result<()> {
+        self.destination.setup_time_bases()?;
+        Ok(())
+    }
+
+    /// Writes the trailer of the output file and finishes it.
+    pub fn write_trailer(&mut self) -> anyhow::Result<()> {
+        self.destination.write_trailer()?;
+        Ok(())
+    }
+
+    /// Reads all packets of the input file, rotates video streams and writes the result into the
+    /// output file. The sink receives a progress event for every packet.
+    pub fn pump_packets<S: ProgressSink>(&mut self, sink: &mut S) -> anyhow::Result<()> {
+        let total_duration = self.source.input_ctx.duration();
+        let start_time = self.source.start_time();
+        let trim = self.transform.trim();
+        // Stream copied video streams are cut at keyframes, so they collect the packets since the
+        // last keyframe until the start of the trimmed range.
+        let mut gops: HashMap<StreamId, Option<Vec<ffmpeg::Packet>>> = match trim {
+            Some(_) => self
+                .source
+                .input_ctx
+                .streams()
+                .filter(|stream| {
+                    stream.parameters().medium() == ffmpeg::media::Type::Video
+                        && !is_attached_picture(stream)
+                        && !self.source.decoders.contains_key(&stream.index().into())
+                })
+                .map(|stream| (stream.index().into(), Some(Vec::new())))
+                .collect(),
+            None => HashMap::new(),
+        };
+
+        for (input_stream, mut packet) in self.source.input_ctx.packets() {
+            if self.cancel_handle.is_cancelled() {
+                return Err(Cancelled.into());
+            }
+
+            if let Some(fraction) = source::fraction(&input_stream, &packet, total_duration) {
+                self.stats.fraction = fraction;
+                sink.progress(&self.stats.progress());
+            }
+
+            let istream_index: StreamId = input_stream.index().into();
+            // Packets of dropped streams are skipped.
+            let Some(&ostream_index) = self.destination.output_streams.get(&istream_index) else {
+                continue;
+            };
+
+            let in_time_base = self.source.time_bases[&istream_index];
+            let out_time_base = self.destination.time_bases[&istream_index];
+
+            match self.source.decoders.get_mut(&istream_index) {
+                Some(decoder) => {
+                    let encoder = self.destination.encoders.get_mut(&istream_index).ok_or(
+                        anyhow::anyhow!("Found missing encoder to corresponding decoder"),
+                    )?;
+                    let filter =
+                        self.destination
+                            .filters
+                            .get_mut(&istream_index)
+                            .ok_or(anyhow::anyhow!(
+                                "Found missing filter to corresponding decoder"
+                            ))?;
+
+                    packet.rescale_ts(input_stream.time_base(), in_time_base);
+
+                    let mut pipe = Pipe {
+                        output_ctx: &mut self.destination.output_ctx,
+                        decoder,
+                        filter,
+                        encoder,
+                        ostream_index,
+                        out_time_base,
+                        stats: &mut self.stats,
+                    };
+                    pipe.decode_packet(&packet)?;
+                    pipe.apply_filter()?;
+                    pipe.encode_packets()?;
+                }
+                None => {
+                    let packets = match (trim, gops.get_mut(&istream_index)) {
+                        // The attached picture is the only packet of its stream, so it's never
+                        // trimmed.
+                        _ if is_attached_picture(&input_stream) => vec![packet],
+                        (Some(trim), Some(gop)) => {
+                            trim_video_packet(gop, packet, in_time_base, start_time, trim)
+                        }
+                        (Some(trim), None) => {
+                            if !trim_packet(&mut packet, in_time_base, start_time, trim) {
+                                continue;
+                            }
+                            vec![packet]
+                        }
+                        (None, _) => vec![packet],
+                    };
+
+                    for mut packet in packets {
+                        if let Some(picture) =
+                            self.destination.attached_pictures.get(&istream_index)
+                        {
+                            let (pts, dts) = (packet.pts(), packet.dts());
+                            packet = picture.clone();
+                            packet.set_pts(pts);
+                            packet.set_dts(dts);
+                        }
+
+                        // Do stream copy on non-video streams.
+                        packet.rescale_ts(in_time_base, out_time_base);
+                        packet.set_position(-1);
+                        packet.set_stream(ostream_index.0);
+                        self.stats.bytes_written += packet.size() as u64;
+                        packet.write_interleaved(&mut self.destination.output_ctx)?;
+                    }
+                }
+            }
+        }
+
+        for (id, decoder) in &mut self.source.decoders {
+            let out_time_base = self.destination.time_bases[id];
+
+            let encoder = self
+                .destination
+                .encoders
+                .get_mut(id)
+                .ok_or(anyhow::anyhow!(
+                    "Found missing encoder to corresponding decoder"
+                ))?;
+            let filter = self.destination.filters.get_mut(id).ok_or(anyhow::anyhow!(
+                "Found missing filter to corresponding decoder"
+            ))?;
+
+            let mut pipe = Pipe {
+                output_ctx: &mut self.destination.output_ctx,
+                decoder,
+                filter,
+                encoder,
+                ostream_index: self.destination.output_streams[id],
+                out_time_base,
+                stats: &mut self.stats,
+            };
+
+            pipe.send_eof_decoder()?;
+            pipe.apply_filter()?;
+            pipe.encode_packets()?;
+
+            pipe.send_eof_encoder()?;
+            pipe.apply_filter()?;
+            pipe.encode_packets()?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Pipe<'a> {
+    output_ctx: &'a mut ffmpeg::format::context::Output,
+    decoder: &'a mut VideoDecoder,
+    filter: &'a mut Filter,
+    encoder: &'a mut VideoEncoder,
+    ostream_index: StreamId,
+    out_time_base: ffmpeg::Rational,
+    stats: &'a mut Stats,
+}
+
+impl<'a> Pipe<'a> {
+    fn send_eof_decoder(&mut self) -> anyhow::Result<()> {
+        self.decoder.send_eof()?;
+        self.decoder.process_frames(|frame| {
+            let timestamp = frame.timestamp();
+            frame.set_pts(timestamp);
+
+            self.filter.send_frame(frame)
+        })?;
+
+        self.filter.send_eof()
+    }
+
+    fn send_eof_encoder(&mut self) -> Result<(), ffmpeg::Error> {
+        self.encoder.send_eof()
+    }
+
+    fn decode_packet(&mut self, packet: &ffmpeg::Packet) -> anyhow::Result<()> {
+        self.decoder.send_packet(packet)?;
+        self.decoder.process_frames(|frame| {
+            let timestamp = frame.timestamp();
+            frame.set_pts(timestamp);
+
+            self.filter.send_frame(frame)
+        })
+    }
+
+    fn apply_filter(&mut self) -> anyhow::Result<()> {
+        self.filter.process_frames(|frame| {
+            self.encoder.send_frame(frame)?;
+            Ok(())
+        })
+    }
+
+    fn encode_packets(&mut self) -> Result<(), ffmpeg::Error> {
+        self.encoder.process_packets(|packet| {
+            packet.set_stream(self.ostream_index.0);
+            // Frames come from the filter in its time base, which the encoder has taken.
+            packet.rescale_ts(self.encoder.time_base(), self.out_time_base);
+
+            self.stats.frames_encoded += 1;
+            self.stats.bytes_written += packet.size() as u64;
+            packet.write_interleaved(self.output_ctx)
+        })
+    }
+}
+
+/// Drops the packet outside of the trimmed range and moves the rest back by its start, so stream
+/// copied tracks match the trimmed video. Returns `false` if the packet is dropped.
+fn trim_packet(
+    packet: &mut ffmpeg::Packet,
+    time_base: ffmpeg::Rational,
+    start_time: f64,
+    (start, end): (Duration, Option<Duration>),
+) -> bool {
+    let Some(time) = packet_time(packet, time_base, start_time) else {
+        return true;
+    };
+
+    if time < start.as_secs_f64() || end.is_some_and(|end| time >= end.as_secs_f64()) {
+        return false;
+    }
+
+    move_back(packet, time_base, start_time, start);
+    true
+}
+
+/// Trims a stream copied video stream like [`trim_packet`], but from the last keyframe before
+/// the start, as the frames after it can't be decoded without it. Packets before the start get
+/// negative timestamps, which MP4 keeps as an edit list, so playback still begins at the start.
+/// The GOP collects packets since that keyframe until the start is reached, and is `None` then.
+/// Returns packets to write.
+fn trim_video_packet(
+    gop: &mut Option<Vec<ffmpeg::Packet>>,
+    packet: ffmpeg::Packet,
+    time_base: ffmpeg::Rational,
+    start_time: f64,
+    (start, end): (Duration, Option<Duration>),
+) -> Vec<ffmpeg::Packet> {
+    let time = packet_time(&packet, time_base, start_time);
+
+    if let Some(pending) = gop
+        && time.is_none_or(|time| time < start.as_secs_f64())
+    {
+        if packet.is_key() {
+            pending.clear();
+        }
+        // Frames before the first keyframe can't be decoded at all.
+        if !pending.is_empty() || packet.is_key() {
+            pending.push(packet);
+        }
+        return Vec::new();
+    }
+
+    // Frames after the start may still refer to earlier ones in decoding order, so packets
+    // before it are kept once the start is reached.
+    if time.is_some_and(|time| end.is_some_and(|end| time >= end.as_secs_f64())) {
+        return Vec::new();
+    }
+
+    let mut packets = gop.take().unwrap_or_default();
+    packets.push(packet);
+    for packet in &mut packets {
+        move_back(packet, time_base, start_time, start);
+    }
+    packets
+}
+
+/// Returns the time of the packet since the beginning of the input file in seconds.
+fn packet_time(
+    packet: &ffmpeg::Packet,
+    time_base: ffmpeg::Rational,
+    start_time: f64,
+) -> Option<f64> {
+    let timestamp = packet.pts().or(packet.dts())?;
+    Some(timestamp as f64 * f64::from(time_base) - start_time)
+}
+
+/// Moves the packet back by the start of the trimmed range.
+fn move_back(
+    packet: &mut ffmpeg::Packet,
+    time_base: ffmpeg::Rational,
+    start_time: f64,
+    start: Duration,
+) {
+    let offset = ((start_time + start.as_secs_f64()) / f64::from(time_base)).round() as i64;
+    packet.set_pts(packet.pts().map(|pts| pts - offset));
+    packet.set_dts(packet.dts().map(|dts| dts - offset));
+}
+
+/// Builder of [`Pipeline`]. Only the input and output files are required, the rest has defaults.
+#[derive(Default)]
+pub struct PipelineBuilder {
+    input: Option<PathBuf>,
+    output: Option<PathBuf>,
+    transform: Transform,
+    method: Method,
+    encoder_options: EncoderOptions,
+    skip_verification: bool,
+    cancel_handle: CancelHandle,
+}
+
+impl PipelineBuilder {
+    /// Sets the video file to rotate.
+    pub fn input<Input: AsRef<Path>>(mut self, input: Input) -> Self {
+        self.input = Some(input.as_ref().to_owned());
+        self
+    }
+
+    /// Sets the file to save the result. The container format is guessed from its extension.
+    pub fn output<Output: AsRef<Path>>(mut self, output: Output) -> Self {
+        self.output = Some(output.as_ref().to_owned());
+        self
+    }
+
+    /// Sets the clockwise rotation. Defaults to [`Rotate::Deg0`].
+    pub fn rotate(mut self, rotate: Rotate) -> Self {
+        self.transform.rotate = rotate;
+        self
+    }
+
+    /// Sets the rotation by an arbitrary angle, which is applied after the one of
+    /// [`PipelineBuilder::rotate`]. It needs re-encoding, so [`Method::Metadata`] fails with it.
+    pub fn tilt(mut self, tilt: Tilt) -> Self {
+        self.transform.tilt = Some(tilt);
+        self
+    }
+
+    /// Adds the operation applied after the rotation and the tilt. Operations need re-encoding,
+    /// so [`Method::Metadata`] fails with them.
+    pub fn operation(mut self, operation: Operation) -> Self {
+        self.transform.operations.push(operation);
+        self
+    }
+
+    /// Sets the size of the output, which the video is scaled to after all operations. It needs
+    /// re-encoding, so [`Method::Metadata`] fails with it.
+    pub fn scale(mut self, scale: Scale) -> Self {
+        self.transform.scale = Some(scale);
+        self
+    }
+
+    /// Adds a time range rotated differently from the rest of the video. It needs re-encoding, so
+    /// [`Method::Metadata`] fails with it. Audio and other stream copied tracks are untouched, so
+    /// they stay in sync.
+    pub fn segment(mut self, segment: Segment) -> Self {
+        self.transform.segments.push(segment);
+        self
+    }
+
+    /// Sets the frame size of the output with segments. Every frame is scaled to fit it and the
+    /// rest is filled with black, so the video is letterboxed or pillarboxed where the
+    /// orientation differs. Defaults to the size of the video outside of segments.
+    pub fn canvas(mut self, width: u32, height: u32) -> Self {
+        self.transform.canvas = Some((width, height));
+        self
+    }
+
+    /// Sets whether interlaced streams are deinterlaced before the rotation. Defaults to
+    /// [`Deinterlace::Auto`]. [`Deinterlace::Always`] needs re-encoding, so [`Method::Metadata`]
+    /// fails with it.
+    pub fn deinterlace(mut self, deinterlace: Deinterlace) -> Self {
+        self.transform.deinterlace = deinterlace;
+        self
+    }
+
+    /// Chooses the video streams to rotate by their indices in the input file, e.g. a single
+    /// angle of a file with several cameras. The rest is stream-copied unchanged. Defaults to all
+    /// video streams.
+    pub fn video_streams(mut self, indices: Vec<usize>) -> Self {
+        self.transform.video_streams = Some(indices);
+        self
+    }
+
+    /// Sets whether attached pictures, e.g. the cover art, are rotated together with the video.
+    /// Otherwise they're stream-copied unchanged. Defaults to `false`.
+    pub fn rotate_attached_pictures(mut self, rotate: bool) -> Self {
+        self.transform.rotate_attached_pictures = rotate;
+        self
+    }
+
+    /// Sets what happens to the stream with the index in the input file: whether it's kept, its
+    /// dispositions and its language. Streams without options are kept as they are.
+    pub fn track(mut self, index: usize, options: TrackOptions) -> Self {
+        self.transform.tracks.insert(index, options);
+        self
+    }
+
+    /// Sets how the rotation is applied. Defaults to [`Method::Reencode`].
+    pub fn method(mut self, method: Method) -> Self {
+        self.method = method;
+        self
+    }
+
+    /// Sets the options of video encoders, which are used when streams are re-encoded.
+    pub fn encoder_options(mut self, encoder_options: EncoderOptions) -> Self {
+        self.encoder_options = encoder_options;
+        self
+    }
+
+    /// Skips the verification of lossless outputs. By default the output is decoded after
+    /// [`Quality::Lossless`] or a lossless codec, and every frame is compared with the input one
+    /// transformed the same way. The pipeline fails if any frame differs.
+    pub fn skip_verification(mut self, skip_verification: bool) -> Self {
+        self.skip_verification = skip_verification;
+        self
+    }
+
+    /// Sets the handle cancelling the pipeline. A new one is made by default, which is available
+    /// via [`Pipeline::cancel_handle`].
+    pub fn cancel_handle(mut self, cancel_handle: CancelHandle) -> Self {
+        self.cancel_handle = cancel_handle;
+        self
+    }
+
+    /// Opens the input file and prepares the output file.
+    pub fn build(mut self) -> anyhow::Result<Pipeline> {
+        let input = self
+            .input
+            .take()
+            .ok_or(anyhow::anyhow!("The input file of the pipeline is not set"))?;
+        let output = self.output.take().ok_or(anyhow::anyhow!(
+            "The output file of the pipeline is not set"
+        ))?;
+
+        Pipeline::init(input, output, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trim_copied_video_at_keyframes() {
+        let time_base = ffmpeg::Rational::new(1, 1);
+        let trim = (Duration::from_secs(4), Some(Duration::from_secs(7)));
+        let mut gop = Some(Vec::new());
+
+        let mut written = Vec::new();
+        for (pts, key) in [
+            (0, false),
+            (1, true),
+            (2, false),
+            (3, true),
+            (4, false),
+            (5, false),
+            (6, true),
+            (7, false),
+        ] {
+            let mut packet = ffmpeg::Packet::empty();
+            packet.set_pts(Some(pts));
+            packet.set_dts(Some(pts));
+            if key {
+                packet.set_flags(ffmpeg::codec::packet::Flags::KEY);
+            }
+            written.extend(
+                trim_video_packet(&mut gop, packet, time_base, 0.0, trim)
+                    .iter()
+                    .map(|packet| packet.pts().unwrap()),
+            );
+        }
+
+        // The stream begins with the keyframe before the start, which is moved before zero.
+        assert_eq!(written, [-1, 0, 1, 2]);
+        assert!(gop.is_none());
+    }
+}
@@ -0,0 +1,243 @@
+use ffmpeg_next as ffmpeg;
+
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::codec::Quality;
+
+/// Receiver of events about the rotation of one file.
+///
+/// All methods do nothing by default, so a sink implements only the events it's interested in.
+/// Progress events come for every packet; wrap the sink into [`Throttle`] to receive them less
+/// often.
+pub trait ProgressSink {
+    /// The input and output files are opened and the rotation begins.
+    fn started(&mut self, _job: &JobInfo) {}
+
+    /// Describes a stream of the input file. It's called for every stream after
+    /// [`ProgressSink::started`].
+    fn stream_info(&mut self, _stream: &StreamInfo) {}
+
+    /// Another packet is processed.
+    fn progress(&mut self, _progress: &Progress) {}
+
+    /// The output file is completely written.
+    fn finished(&mut self, _progress: &Progress) {}
+
+    /// The rotation is stopped by the error.
+    fn failed(&mut self, _error: &anyhow::Error) {}
+}
+
+impl<S: ProgressSink + ?Sized> ProgressSink for &mut S {
+    fn started(&mut self, job: &JobInfo) {
+        (**self).started(job);
+    }
+
+    fn stream_info(&mut self, stream: &StreamInfo) {
+        (**self).stream_info(stream);
+    }
+
+    fn progress(&mut self, progress: &Progress) {
+        (**self).progress(progress);
+    }
+
+    fn finished(&mut self, progress: &Progress) {
+        (**self).finished(progress);
+    }
+
+    fn failed(&mut self, error: &anyhow::Error) {
+        (**self).failed(error);
+    }
+}
+
+/// The sink ignoring all events.
+impl ProgressSink for () {}
+
+/// Files of the rotation.
+#[derive(Debug)]
+pub struct JobInfo<'a> {
+    /// The video file being rotated.
+    pub input: &'a Path,
+    /// The file the result is written into.
+    pub output: &'a Path,
+    /// The duration of the input file, if the container knows it.
+    pub duration: Option<Duration>,
+}
+
+/// The media type of a stream.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StreamKind {
+    /// Video frames, including pictures attached to the file.
+    Video,
+    /// Audio samples.
+    Audio,
+    /// Subtitles, either text or bitmaps.
+    Subtitle,
+    /// Data such as timecodes or GPS tracks of action cameras.
+    Data,
+    /// Files attached to the container, e.g. fonts of Matroska subtitles.
+    Attachment,
+    /// Anything FFmpeg doesn't recognize.
+    Unknown,
+}
+
+impl From<ffmpeg::media::Type> for StreamKind {
+    fn from(value: ffmpeg::media::Type) -> Self {
+        match value {
+            ffmpeg::media::Type::Video => StreamKind::Video,
+            ffmpeg::media::Type::Audio => StreamKind::Audio,
+            ffmpeg::media::Type::Subtitle => StreamKind::Subtitle,
+            ffmpeg::media::Type::Data => StreamKind::Data,
+            ffmpeg::media::Type::Attachment => StreamKind::Attachment,
+            ffmpeg::media::Type::Unknown => StreamKind::Unknown,
+        }
+    }
+}
+
+/// A stream of the input file and what happens to it.
+#[derive(Debug)]
+pub struct StreamInfo {
+    /// The index of the stream in the input file.
+    pub index: usize,
+    /// The media type of the stream.
+    pub kind: StreamKind,
+    /// The name of the input codec, e.g. `h264`.
+    pub codec: String,
+    /// The stream is re-encoded, otherwise it's stream copied.
+    pub reencoded: bool,
+    /// The stream isn't written into the output.
+    pub dropped: bool,
+    /// The rate control of the re-encoded stream. It's never [`Quality::MatchSource`], which is
+    /// replaced with the values chosen for the stream.
+    pub quality: Option<Quality>,
+}
+
+/// The state of the rotation.
+#[derive(Clone, Debug)]
+pub struct Progress {
+    /// The processed part of the input file from 0.0 to 1.0. It counts both passes of two-pass
+    /// encoding and the verification of lossless outputs.
+    pub fraction: f64,
+    /// Video frames encoded over all re-encoded streams.
+    pub frames_encoded: u64,
+    /// Encoded frames per second.
+    pub fps: f64,
+    /// Size of packets written into the output file.
+    pub bytes_written: u64,
+    /// Time since the rotation began.
+    pub elapsed: Duration,
+    /// Estimated time until the end, unknown at the very beginning.
+    pub eta: Option<Duration>,
+}
+
+/// Counters of the running pipeline, from which [`Progress`] is made.
+pub(crate) struct Stats {
+    started_at: Instant,
+    /// The number of finished passes over the video and the total one.
+    passes_done: u32,
+    passes: u32,
+    /// The processed part of the current pass.
+    pub(crate) fraction: f64,
+    pub(crate) frames_encoded: u64,
+    pub(crate) bytes_written: u64,
+}
+
+impl Stats {
+    pub(crate) fn new(passes: u32) -> Self {
+        Self {
+            started_at: Instant::now(),
+            passes_done: 0,
+            passes,
+            fraction: 0.0,
+            frames_encoded: 0,
+            bytes_written: 0,
+        }
+    }
+
+    pub(crate) fn next_pass(&mut self) {
+        self.passes_done += 1;
+        self.fraction = 0.0;
+    }
+
+    pub(crate) fn progress(&self) -> Progress {
+        let elapsed = self.started_at.elapsed();
+        let seconds = elapsed.as_secs_f64();
+        let fraction = (f64::from(self.passes_done) + self.fraction) / f64::from(self.passes);
+
+        let fps = if seconds > 0.0 {
+            self.frames_encoded as f64 / seconds
+        } else {
+            0.0
+        };
+
+        let eta = (fraction > 0.0)
+            .then(|| Duration::try_from_secs_f64(seconds * (1.0 - fraction) / fraction).ok())
+            .flatten();
+
+        Progress {
+            fraction,
+            frames_encoded: self.frames_encoded,
+            fps,
+            bytes_written: self.bytes_written,
+            elapsed,
+            eta,
+        }
+    }
+}
+
+/// Passes progress events to the inner sink not more often than once per interval. Other events
+/// are passed as is.
+pub struct Throttle<S> {
+    sink: S,
+    interval: Duration,
+    last_sent: Option<Instant>,
+}
+
+impl<S: ProgressSink> Throttle<S> {
+    /// Wraps the sink, which receives at most one progress event per interval.
+    pub fn new(sink: S, interval: Duration) -> Self {
+        Self {
+            sink,
+            interval,
+            last_sent: None,
+        }
+    }
+
+    /// Returns the wrapped sink.
+    pub fn into_inner(self) -> S {
+        self.sink
+    }
+}
+
+impl<S: ProgressSink> ProgressSink for Throttle<S> {
+    fn started(&mut self, job: &JobInfo) {
+        self.sink.started(job);
+    }
+
+    fn stream_info(&mut self, stream: &StreamInfo) {
+        self.sink.stream_info(stream);
+    }
+
+    fn progress(&mut self, progress: &Progress) {
+        let now = Instant::now();
+        if self
+            .last_sent
+            .is_some_and(|last_sent| now.duration_since(last_sent) < self.interval)
+        {
+            return;
+        }
+
+        self.last_sent = Some(now);
+        self.sink.progress(progress);
+    }
+
+    fn finished(&mut self, progress: &Progress) {
+        self.sink.finished(progress);
+    }
+
+    fn failed(&mut self, error: &anyhow::Error) {
+        self.sink.failed(error);
+    }
+}
@@ -0,0 +1,511 @@
+use ffmpeg_next as ffmpeg;
+
+use std::str::FromStr;
+
+/// Rotation or mirroring of video. Together they make all eight orientations of a rectangle.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum Rotate {
+    /// Keeps the orientation.
+    #[default]
+    Deg0,
+    /// 90° clockwise.
+    Deg90,
+    /// Upside down.
+    Deg180,
+    /// 270° clockwise, i.e. 90° counterclockwise.
+    Deg270,
+    /// Mirrors left and right.
+    HFlip,
+    /// Mirrors top and bottom.
+    VFlip,
+    /// Mirrors along the main diagonal, i.e. 90° clockwise and then horizontal flip.
+    Transpose,
+    /// Mirrors along the anti-diagonal, i.e. 270° clockwise and then horizontal flip.
+    Transverse,
+}
+
+impl Rotate {
+    /// All eight orientations.
+    pub const ALL: [Rotate; 8] = [
+        Rotate::Deg0,
+        Rotate::Deg90,
+        Rotate::Deg180,
+        Rotate::Deg270,
+        Rotate::HFlip,
+        Rotate::VFlip,
+        Rotate::Transpose,
+        Rotate::Transverse,
+    ];
+
+    /// Returns the shortest chain of filters making the transform.
+    pub(crate) fn as_filter(&self) -> &str {
+        match self {
+            Rotate::Deg0 => "null",
+            Rotate::Deg90 => "transpose=clock",
+            Rotate::Deg180 => "hflip,vflip",
+            Rotate::Deg270 => "transpose=cclock",
+            Rotate::HFlip => "hflip",
+            Rotate::VFlip => "vflip",
+            Rotate::Transpose => "transpose=cclock_flip",
+            Rotate::Transverse => "transpose=clock_flip",
+        }
+    }
+
+    pub(crate) fn is_axis_flips(&self) -> bool {
+        matches!(
+            self,
+            Rotate::Deg90 | Rotate::Deg270 | Rotate::Transpose | Rotate::Transverse
+        )
+    }
+
+    /// Whether the transform mirrors the video.
+    pub(crate) fn is_mirrored(&self) -> bool {
+        self.as_parts().0
+    }
+
+    /// Returns the transform as the horizontal flip, if any, followed by the number of clockwise
+    /// quarter turns.
+    fn as_parts(&self) -> (bool, u8) {
+        match self {
+            Rotate::Deg0 => (false, 0),
+            Rotate::Deg90 => (false, 1),
+            Rotate::Deg180 => (false, 2),
+            Rotate::Deg270 => (false, 3),
+            Rotate::HFlip => (true, 0),
+            Rotate::Transverse => (true, 1),
+            Rotate::VFlip => (true, 2),
+            Rotate::Transpose => (true, 3),
+        }
+    }
+
+    fn from_parts(mirrored: bool, quarter_turns: u8) -> Self {
+        match (mirrored, quarter_turns % 4) {
+            (false, 0) => Rotate::Deg0,
+            (false, 1) => Rotate::Deg90,
+            (false, 2) => Rotate::Deg180,
+            (false, _) => Rotate::Deg270,
+            (true, 0) => Rotate::HFlip,
+            (true, 1) => Rotate::Transverse,
+            (true, 2) => Rotate::VFlip,
+            (true, _) => Rotate::Transpose,
+        }
+    }
+
+    /// Returns the rotation angle in clockwise degrees, not counting the mirroring.
+    pub(crate) fn degrees(&self) -> f64 {
+        f64::from(self.as_parts().1) * 90.0
+    }
+
+    /// Rounds the clockwise degrees to the nearest quarter turn.
+    pub(crate) fn from_degrees(degrees: f64) -> Self {
+        let quarter_turns = ((degrees / 90.0).round() as i64).rem_euclid(4);
+        Rotate::from_parts(false, quarter_turns as u8)
+    }
+
+    /// Returns the transform made by applying `self` and then `other`.
+    pub(crate) fn then(&self, other: &Rotate) -> Self {
+        let (first_mirrored, first_turns) = self.as_parts();
+        let (second_mirrored, second_turns) = other.as_parts();
+
+        // The flip of the second transform turns the rotation of the first one backwards.
+        let first_turns = if second_mirrored {
+            4 - first_turns
+        } else {
+            first_turns
+        };
+
+        Rotate::from_parts(
+            first_mirrored != second_mirrored,
+            first_turns + second_turns,
+        )
+    }
+
+    /// Returns the rotation in clockwise degrees and whether the horizontal flip is needed for
+    /// the display matrix describing the transform.
+    pub(crate) fn display_matrix(&self) -> (f64, bool) {
+        match self {
+            Rotate::HFlip => (0.0, true),
+            Rotate::Transpose => (90.0, true),
+            Rotate::VFlip => (180.0, true),
+            Rotate::Transverse => (270.0, true),
+            rotate => (rotate.degrees(), false),
+        }
+    }
+}
+
+/// Parses `0`, `90`, `180`, `270`, `hflip`, `vflip`, `transpose` or `transverse`.
+impl FromStr for Rotate {
+    type Err = anyhow::Error;
+
+    fn from_str(rotation: &str) -> Result<Self, Self::Err> {
+        match rotation {
+            "0" => Ok(Rotate::Deg0),
+            "90" => Ok(Rotate::Deg90),
+            "180" => Ok(Rotate::Deg180),
+            "270" => Ok(Rotate::Deg270),
+            "hflip" => Ok(Rotate::HFlip),
+            "vflip" => Ok(Rotate::VFlip),
+            "transpose" => Ok(Rotate::Transpose),
+            "transverse" => Ok(Rotate::Transverse),
+            _ => anyhow::bail!("Unknown rotation {rotation}"),
+        }
+    }
+}
+
+/// Rotation by any angle, e.g. to level the horizon of handheld footage. It's applied after
+/// [`Rotate`].
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Tilt {
+    /// Clockwise degrees.
+    pub degrees: f64,
+    /// What happens to the corners the rotated frame uncovers.
+    pub fill: Fill,
+}
+
+/// How the area uncovered by [`Tilt`] or [`crate::Operation::Aspect`] is handled.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum Fill {
+    /// Crops the video to the largest rectangle of the same aspect ratio inside the tilted frame,
+    /// or of the target aspect ratio.
+    #[default]
+    Crop,
+    /// Grows the frame to fit the whole video and fills the uncovered area with the RGB color.
+    Color([u8; 3]),
+    /// Grows the frame like [`Fill::Color`], but fills the uncovered area with a blurred copy of
+    /// the video.
+    Blur,
+}
+
+/// Parses `crop`, `blur` or a color: `black`, `white` or `#rrggbb`.
+impl FromStr for Fill {
+    type Err = anyhow::Error;
+
+    fn from_str(fill: &str) -> Result<Self, Self::Err> {
+        match fill {
+            "crop" => Ok(Fill::Crop),
+            "blur" => Ok(Fill::Blur),
+            _ => Ok(Fill::Color(
+                parse_color(fill).map_err(|_| anyhow::anyhow!("Unknown fill {fill}"))?,
+            )),
+        }
+    }
+}
+
+/// Parses `black`, `white` or `#rrggbb` into RGB.
+pub(crate) fn parse_color(color: &str) -> anyhow::Result<[u8; 3]> {
+    match color {
+        "black" => return Ok([0, 0, 0]),
+        "white" => return Ok([255, 255, 255]),
+        _ => {}
+    }
+
+    let rgb = color
+        .strip_prefix('#')
+        .filter(|rgb| rgb.len() == 6)
+        .and_then(|rgb| u32::from_str_radix(rgb, 16).ok())
+        .ok_or(anyhow::anyhow!("Unknown color {color}"))?;
+    let [_, red, green, blue] = rgb.to_be_bytes();
+
+    Ok([red, green, blue])
+}
+
+impl Tilt {
+    /// Returns the size of the tilted frame, rounded down to even numbers as encoders need.
+    pub(crate) fn output_size(&self, width: u32, height: u32) -> (u32, u32) {
+        let (width, height) = (f64::from(width), f64::from(height));
+        let radians = self.degrees.to_radians();
+        let (sin, cos) = (radians.sin().abs(), radians.cos().abs());
+
+        let (output_width, output_height) = match self.fill {
+            Fill::Crop => {
+                let scale = f64::min(
+                    width / (width * cos + height * sin),
+                    height / (width * sin + height * cos),
+                );
+                (width * scale, height * scale)
+            }
+            Fill::Color(_) | Fill::Blur => (width * cos + height * sin, width * sin + height * cos),
+        };
+
+        (round_to_even(output_width), round_to_even(output_height))
+    }
+
+    /// Returns the filters tilting frames of the given size and pixel format.
+    pub(crate) fn as_filter(&self, width: u32, height: u32, pixel_format: &str) -> String {
+        let (output_width, output_height) = self.output_size(width, height);
+        let rotate = format!(
+            "rotate=angle={:.8}:out_w={output_width}:out_h={output_height}",
+            self.degrees.to_radians()
+        );
+
+        match self.fill {
+            Fill::Crop => rotate,
+            Fill::Color([red, green, blue]) => {
+                format!("{rotate}:fillcolor=0x{red:02x}{green:02x}{blue:02x}")
+            }
+            // The tilted video is transparent in the corners and laid over the blurred one
+            // stretched to the whole frame.
+            Fill::Blur => format!(
+                "split[tilt_bg][tilt_fg];\
+                 [tilt_bg]scale={output_width}:{output_height}:force_original_aspect_ratio=increase,\
+                 crop={output_width}:{output_height},gblur=sigma=30[tilt_blurred];\
+                 [tilt_fg]format=yuva444p,{rotate}:fillcolor=none[tilt_rotated];\
+                 [tilt_blurred][tilt_rotated]overlay=0:0,format={pixel_format}"
+            ),
+        }
+    }
+}
+
+pub(crate) fn round_to_even(value: f64) -> u32 {
+    ((value / 2.0).floor() as u32 * 2).max(2)
+}
+
+/// The way how the rotation is applied to the video streams.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum Method {
+    /// Decode, rotate and encode the video again.
+    #[default]
+    Reencode,
+    /// Copy all streams as is and only change the display matrix, so players show the video
+    /// rotated. It's lossless and much faster, but some players ignore the matrix.
+    Metadata,
+    /// Re-encode only the video streams having a display matrix, so every video is physically
+    /// upright and has no rotation metadata. Streams without the matrix are stream copied.
+    BakeIn,
+}
+
+/// Parses `reencode` or `metadata`. [`Method::BakeIn`] has no spelling, it comes with rotating
+/// upright.
+impl FromStr for Method {
+    type Err = anyhow::Error;
+
+    fn from_str(method: &str) -> Result<Self, Self::Err> {
+        match method {
+            "reencode" => Ok(Method::Reencode),
+            "metadata" => Ok(Method::Metadata),
+            _ => anyhow::bail!("Unknown method {method}"),
+        }
+    }
+}
+
+impl Method {
+    pub(crate) fn needs_decoding(&self, orientation: &Rotate, transformed: bool) -> bool {
+        match self {
+            Method::Reencode => true,
+            Method::Metadata => false,
+            Method::BakeIn => *orientation != Rotate::Deg0 || transformed,
+        }
+    }
+}
+
+/// Reads the display matrix of the stream parameters.
+fn display_matrix(parameters: &ffmpeg::codec::Parameters) -> Option<[i32; 9]> {
+    let data = unsafe {
+        let parameters = parameters.as_ptr();
+        let side_data = ffmpeg::ffi::av_packet_side_data_get(
+            (*parameters).coded_side_data,
+            (*parameters).nb_coded_side_data,
+            ffmpeg::ffi::AVPacketSideDataType::AV_PKT_DATA_DISPLAYMATRIX,
+        );
+        if side_data.is_null() || (*side_data).size < DISPLAY_MATRIX_SIZE {
+            return None;
+        }
+        std::slice::from_raw_parts((*side_data).data, DISPLAY_MATRIX_SIZE)
+    };
+
+    let mut matrix = [0; 9];
+    for (value, bytes) in matrix.iter_mut().zip(data.chunks_exact(4)) {
+        *value = i32::from_ne_bytes(bytes.try_into().unwrap());
+    }
+
+    Some(matrix)
+}
+
+/// Reads the display matrix of the stream parameters and returns its rotation in clockwise
+/// degrees.
+pub(crate) fn display_rotation(parameters: &ffmpeg::codec::Parameters) -> Option<f64> {
+    let matrix = display_matrix(parameters)?;
+
+    let counterclockwise = unsafe { ffmpeg::ffi::av_display_rotation_get(matrix.as_ptr()) };
+    if counterclockwise.is_nan() {
+        return None;
+    }
+
+    Some(-counterclockwise)
+}
+
+/// Reads the display matrix of the stream parameters and returns the transform players apply,
+/// with the rotation rounded to quarter turns.
+pub(crate) fn display_orientation(parameters: &ffmpeg::codec::Parameters) -> Option<Rotate> {
+    let rotate = Rotate::from_degrees(display_rotation(parameters)?);
+    let matrix = display_matrix(parameters)?;
+
+    // Mirrored matrices are told apart the same way as FFmpeg does when it rotates automatically.
+    let orientation = match rotate {
+        Rotate::Deg0 if matrix[4] < 0 => Rotate::VFlip,
+        Rotate::Deg90 if matrix[3] > 0 => Rotate::Transpose,
+        Rotate::Deg180 => match (matrix[0] < 0, matrix[4] < 0) {
+            (true, true) => Rotate::Deg180,
+            (true, false) => Rotate::HFlip,
+            (false, true) => Rotate::VFlip,
+            (false, false) => Rotate::Deg0,
+        },
+        Rotate::Deg270 if matrix[3] < 0 => Rotate::Transverse,
+        rotate => rotate,
+    };
+
+    Some(orientation)
+}
+
+/// Replaces the display matrix of the stream parameters with one rotating the video by the given
+/// clockwise degrees and then flipping it horizontally if requested. The matrix is removed
+/// entirely for the identity transform.
+pub(crate) fn set_display_matrix(
+    parameters: &mut ffmpeg::codec::Parameters,
+    clockwise_degrees: f64,
+    hflip: bool,
+) -> anyhow::Result<()> {
+    let clockwise_degrees = clockwise_degrees.rem_euclid(360.0);
+
+    unsafe {
+        let parameters = parameters.as_mut_ptr();
+        ffmpeg::ffi::av_packet_side_data_remove(
+            (*parameters).coded_side_data,
+            &mut (*parameters).nb_coded_side_data,
+            ffmpeg::ffi::AVPacketSideDataType::AV_PKT_DATA_DISPLAYMATRIX,
+        );
+
+        if clockwise_degrees == 0.0 && !hflip {
+            return Ok(());
+        }
+
+        let side_data = ffmpeg::ffi::av_packet_side_data_new(
+            &mut (*parameters).coded_side_data,
+            &mut (*parameters).nb_coded_side_data,
+            ffmpeg::ffi::AVPacketSideDataType::AV_PKT_DATA_DISPLAYMATRIX,
+            DISPLAY_MATRIX_SIZE,
+            0,
+        );
+        if side_data.is_null() {
+            anyhow::bail!("Cannot allocate display matrix for the output stream");
+        }
+
+        // FFmpeg counts the display rotation counterclockwise.
+        ffmpeg::ffi::av_display_rotation_set((*side_data).data.cast(), -clockwise_degrees);
+        ffmpeg::ffi::av_display_matrix_flip((*side_data).data.cast(), hflip.into(), 0);
+    }
+
+    Ok(())
+}
+
+const DISPLAY_MATRIX_SIZE: usize = std::mem::size_of::<[i32; 9]>();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the chain of filters of [`Rotate::as_filter`] on the grid of pixels the way FFmpeg
+    /// does.
+    fn apply(filters: &str, grid: &[Vec<u32>]) -> Vec<Vec<u32>> {
+        filters.split(',').fold(grid.to_vec(), |grid, filter| {
+            let (height, width) = (grid.len(), grid[0].len());
+            let make = |rows: usize, columns: usize, pixel: &dyn Fn(usize, usize) -> u32| {
+                (0..rows)
+                    .map(|row| (0..columns).map(|column| pixel(row, column)).collect())
+                    .collect()
+            };
+
+            match filter {
+                "null" => grid,
+                "hflip" => make(height, width, &|row, column| grid[row][width - 1 - column]),
+                "vflip" => make(height, width, &|row, column| grid[height - 1 - row][column]),
+                "transpose=clock" => {
+                    make(width, height, &|row, column| grid[height - 1 - column][row])
+                }
+                "transpose=cclock" => {
+                    make(width, height, &|row, column| grid[column][width - 1 - row])
+                }
+                "transpose=cclock_flip" => make(width, height, &|row, column| grid[column][row]),
+                "transpose=clock_flip" => make(width, height, &|row, column| {
+                    grid[height - 1 - column][width - 1 - row]
+                }),
+                _ => panic!("Unknown filter {filter}"),
+            }
+        })
+    }
+
+    #[test]
+    fn then_matches_composed_filters() {
+        let grid = vec![vec![0, 1, 2], vec![3, 4, 5]];
+
+        for first in Rotate::ALL {
+            for second in Rotate::ALL {
+                let composed = apply(second.as_filter(), &apply(first.as_filter(), &grid));
+                assert_eq!(
+                    apply(first.then(&second).as_filter(), &grid),
+                    composed,
+                    "{first:?} then {second:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn filters_match_orientations() {
+        let grid = vec![vec![0, 1, 2], vec![3, 4, 5]];
+
+        assert_eq!(
+            apply(Rotate::Deg90.as_filter(), &grid),
+            [[3, 0], [4, 1], [5, 2]]
+        );
+        assert_eq!(
+            apply(Rotate::Deg270.as_filter(), &grid),
+            [[2, 5], [1, 4], [0, 3]]
+        );
+        assert_eq!(
+            apply(Rotate::Transpose.as_filter(), &grid),
+            [[0, 3], [1, 4], [2, 5]]
+        );
+        assert_eq!(
+            apply(Rotate::Transverse.as_filter(), &grid),
+            [[5, 2], [4, 1], [3, 0]]
+        );
+    }
+
+    #[test]
+    fn display_matrix_round_trips() {
+        for rotate in Rotate::ALL {
+            let mut parameters = ffmpeg::codec::Parameters::new();
+            let (degrees, hflip) = rotate.display_matrix();
+            set_display_matrix(&mut parameters, degrees, hflip).unwrap();
+
+            assert_eq!(
+                display_orientation(&parameters).unwrap_or(Rotate::Deg0),
+                rotate
+            );
+        }
+    }
+
+    #[test]
+    fn tilt_output_size() {
+        let tilt = |degrees, fill| Tilt { degrees, fill };
+
+        assert_eq!(tilt(0.0, Fill::Crop).output_size(1920, 1080), (1920, 1080));
+        assert_eq!(tilt(0.0, Fill::Blur).output_size(1920, 1080), (1920, 1080));
+        assert_eq!(
+            tilt(90.0, Fill::Color([0, 0, 0])).output_size(1920, 1080),
+            (1080, 1920)
+        );
+        assert_eq!(tilt(-45.0, Fill::Blur).output_size(100, 100), (140, 140));
+        assert_eq!(tilt(45.0, Fill::Crop).output_size(100, 100), (70, 70));
+        // The cropped frame keeps the aspect ratio.
+        assert_eq!(tilt(10.0, Fill::Crop).output_size(1920, 1080), (1484, 834));
+    }
+
+    #[test]
+    fn parse_methods() {
+        assert_eq!("reencode".parse::<Method>().unwrap(), Method::Reencode);
+        assert_eq!("metadata".parse::<Method>().unwrap(), Method::Metadata);
+        assert!("bake-in".parse::<Method>().is_err());
+    }
+}
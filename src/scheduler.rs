@@ -0,0 +1,326 @@
+use std::{
+    collections::VecDeque,
+    num::NonZeroUsize,
+    panic::AssertUnwindSafe,
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+};
+
+use crate::cancel::{CancelHandle, Cancelled};
+
+type Task = Box<dyn FnOnce(CancelHandle) -> anyhow::Result<()> + Send>;
+type OnFinished<K> = Box<dyn Fn(K, anyhow::Result<()>) + Send + Sync>;
+
+/// Runs jobs in background threads in the order they are submitted, but not more than the
+/// concurrency limit at once.
+///
+/// Every job is identified by a key chosen by the caller. The callback given to
+/// [`Scheduler::new`] is called from the thread of the job once it's finished, or from the
+/// cancelling thread for jobs which are cancelled before they start.
+///
+/// Cancelling waits until the threads of running jobs are finished, so do it outside of jobs and
+/// of UI event loops. Dropping the scheduler cancels all jobs the same way.
+pub struct Scheduler<K: PartialEq + Clone + Send + 'static> {
+    shared: Arc<Shared<K>>,
+}
+
+struct Shared<K> {
+    state: Mutex<State<K>>,
+    on_finished: OnFinished<K>,
+}
+
+struct State<K> {
+    concurrency: usize,
+    queue: VecDeque<QueuedJob<K>>,
+    running: Vec<RunningJob<K>>,
+    /// Threads of finished jobs, which may still be reporting their results.
+    finished: Vec<JoinHandle<()>>,
+}
+
+struct RunningJob<K> {
+    key: K,
+    cancel_handle: CancelHandle,
+    /// It's taken by whoever is going to join the thread.
+    thread: Option<JoinHandle<()>>,
+}
+
+struct QueuedJob<K> {
+    key: K,
+    task: Task,
+    cancel_handle: CancelHandle,
+}
+
+impl<K: PartialEq + Clone + Send + 'static> Scheduler<K> {
+    /// Makes an empty scheduler running up to `concurrency` jobs at once, at least one. The
+    /// callback receives the key and the result of every finished job.
+    pub fn new<F: Fn(K, anyhow::Result<()>) + Send + Sync + 'static>(
+        concurrency: usize,
+        on_finished: F,
+    ) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    concurrency: concurrency.max(1),
+                    queue: VecDeque::new(),
+                    running: vec![],
+                    finished: vec![],
+                }),
+                on_finished: Box::new(on_finished),
+            }),
+        }
+    }
+
+    /// Returns the number of jobs worth running at once on this machine. Every encoder is
+    /// multithreaded itself, so only a quarter of cores is taken.
+    pub fn default_concurrency() -> usize {
+        let cores = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        (cores / 4).max(1)
+    }
+
+    /// Changes the limit of running jobs. Already running jobs aren't stopped when it's
+    /// decreased.
+    pub fn set_concurrency(&self, concurrency: usize) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.concurrency = concurrency.max(1);
+        dispatch(&self.shared, &mut state);
+    }
+
+    /// Puts the job at the end of the queue. The job receives the handle, which is cancelled by
+    /// [`Scheduler::cancel`].
+    pub fn submit<F: FnOnce(CancelHandle) -> anyhow::Result<()> + Send + 'static>(
+        &self,
+        key: K,
+        task: F,
+    ) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.queue.push_back(QueuedJob {
+            key,
+            task: Box::new(task),
+            cancel_handle: CancelHandle::new(),
+        });
+        dispatch(&self.shared, &mut state);
+    }
+
+    /// Cancels the running job and waits for its thread, or removes the queued job. Either
+    /// finishes with [`Cancelled`].
+    pub fn cancel(&self, key: &K) {
+        let mut state = self.shared.state.lock().unwrap();
+
+        if let Some(job) = state.running.iter_mut().find(|job| &job.key == key) {
+            job.cancel_handle.cancel();
+            let thread = job.thread.take();
+            drop(state);
+
+            if let Some(thread) = thread {
+                let _ = thread.join();
+            }
+            return;
+        }
+
+        let Some(position) = state.queue.iter().position(|job| &job.key == key) else {
+            return;
+        };
+        let job = state.queue.remove(position).unwrap();
+        drop(state);
+
+        (self.shared.on_finished)(job.key, Err(Cancelled.into()));
+    }
+
+    /// Cancels all running and queued jobs and waits for their threads.
+    pub fn cancel_all(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+
+        let mut threads = std::mem::take(&mut state.finished);
+        for job in &mut state.running {
+            job.cancel_handle.cancel();
+            threads.extend(job.thread.take());
+        }
+        let queue = std::mem::take(&mut state.queue);
+        drop(state);
+
+        for job in queue {
+            (self.shared.on_finished)(job.key, Err(Cancelled.into()));
+        }
+        for thread in threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl<K: PartialEq + Clone + Send + 'static> Drop for Scheduler<K> {
+    fn drop(&mut self) {
+        self.cancel_all();
+    }
+}
+
+/// Starts queued jobs while there are free slots.
+fn dispatch<K: Clone + PartialEq + Send + 'static>(shared: &Arc<Shared<K>>, state: &mut State<K>) {
+    while state.running.len() < state.concurrency {
+        let Some(job) = state.queue.pop_front() else {
+            break;
+        };
+        let key = job.key.clone();
+        let cancel_handle = job.cancel_handle.clone();
+
+        let shared = shared.clone();
+        // The thread waits for the lock held here before it's done, so it's always found among
+        // running jobs.
+        let thread = std::thread::spawn(move || {
+            let QueuedJob {
+                key,
+                task,
+                cancel_handle,
+            } = job;
+
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| task(cancel_handle)))
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("The job has crashed")));
+
+            let mut state = shared.state.lock().unwrap();
+            if let Some(position) = state.running.iter().position(|job| job.key == key) {
+                let job = state.running.remove(position);
+                state.finished.retain(|thread| !thread.is_finished());
+                state.finished.extend(job.thread);
+            }
+            dispatch(&shared, &mut state);
+            drop(state);
+
+            (shared.on_finished)(key, result);
+        });
+        state.running.push(RunningJob {
+            key,
+            cancel_handle,
+            thread: Some(thread),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            mpsc,
+        },
+        time::Duration,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn wait_for_cancel(cancel_handle: CancelHandle) -> anyhow::Result<()> {
+        while !cancel_handle.is_cancelled() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        Err(Cancelled.into())
+    }
+
+    #[test]
+    fn runs_jobs_in_order() {
+        let (sender, receiver) = mpsc::channel();
+        let scheduler = Scheduler::new(1, move |key, result| sender.send((key, result)).unwrap());
+        let started = Arc::new(Mutex::new(vec![]));
+
+        for key in 0..5 {
+            let started = started.clone();
+            scheduler.submit(key, move |_| {
+                started.lock().unwrap().push(key);
+                Ok(())
+            });
+        }
+
+        let mut finished: Vec<_> = (0..5)
+            .map(|_| {
+                let (key, result) = receiver.recv_timeout(TIMEOUT).unwrap();
+                result.unwrap();
+                key
+            })
+            .collect();
+        finished.sort();
+        assert_eq!(finished, [0, 1, 2, 3, 4]);
+        assert_eq!(*started.lock().unwrap(), [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn limits_running_jobs() {
+        let (sender, receiver) = mpsc::channel();
+        let scheduler = Scheduler::new(2, move |key, result| sender.send((key, result)).unwrap());
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+
+        for key in 0..6 {
+            let running = running.clone();
+            let max_running = max_running.clone();
+            scheduler.submit(key, move |_| {
+                let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now_running, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(10));
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok(())
+            });
+        }
+
+        for _ in 0..6 {
+            receiver.recv_timeout(TIMEOUT).unwrap().1.unwrap();
+        }
+        assert!(max_running.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[test]
+    fn cancels_running_and_queued_jobs() {
+        let (sender, receiver) = mpsc::channel();
+        let scheduler = Scheduler::new(1, move |key, result| sender.send((key, result)).unwrap());
+        let started = Arc::new(AtomicBool::new(false));
+
+        scheduler.submit(0, wait_for_cancel);
+        let queued_started = started.clone();
+        scheduler.submit(1, move |_| {
+            queued_started.store(true, Ordering::SeqCst);
+            Ok(())
+        });
+
+        // The queued job is reported right away, and the running one before its thread is
+        // joined.
+        scheduler.cancel(&1);
+        let (key, result) = receiver.try_recv().unwrap();
+        assert_eq!(key, 1);
+        assert!(result.unwrap_err().is::<Cancelled>());
+
+        scheduler.cancel(&0);
+        let (key, result) = receiver.try_recv().unwrap();
+        assert_eq!(key, 0);
+        assert!(result.unwrap_err().is::<Cancelled>());
+
+        assert!(!started.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn dropping_cancels_all_jobs() {
+        let (sender, receiver) = mpsc::channel();
+        let scheduler = Scheduler::new(1, move |key, result| sender.send((key, result)).unwrap());
+
+        scheduler.submit(0, wait_for_cancel);
+        scheduler.submit(1, wait_for_cancel);
+        drop(scheduler);
+
+        let mut finished: Vec<_> = receiver.try_iter().collect();
+        finished.sort_by_key(|(key, _)| *key);
+        assert_eq!(finished.len(), 2);
+        for (key, (finished_key, result)) in finished.into_iter().enumerate() {
+            assert_eq!(finished_key, key);
+            assert!(result.unwrap_err().is::<Cancelled>());
+        }
+    }
+
+    #[test]
+    fn reports_crashed_jobs() {
+        let (sender, receiver) = mpsc::channel();
+        let scheduler = Scheduler::new(1, move |key, result| sender.send((key, result)).unwrap());
+
+        scheduler.submit(0, |_| panic!("The job panics"));
+
+        let (key, result) = receiver.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(key, 0);
+        assert!(result.is_err());
+    }
+}
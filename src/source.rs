@@ -0,0 +1,381 @@
+use ffmpeg_next as ffmpeg;
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    StreamId,
+    codec::VideoDecoder,
+    cover::is_attached_picture,
+    rotate::{Method, Rotate, display_orientation},
+    transform::{Deinterlace, Transform},
+};
+
+/// Properties of a decoded video stream, which the quality of the output is matched to.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct VideoProperties {
+    pub(crate) codec: ffmpeg::codec::Id,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    /// The average frame rate, unless the stream doesn't know a sane one.
+    pub(crate) frame_rate: Option<ffmpeg::Rational>,
+    /// Bits per second, if the stream or the container knows it.
+    pub(crate) bit_rate: Option<usize>,
+}
+
+/// Stream-wide HDR metadata, which encoders write into the bitstream and muxers into the
+/// container. Both are kept in the layout of FFmpeg structures.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct HdrMetadata {
+    /// `AVMasteringDisplayMetadata` of the display the video was graded on.
+    pub(crate) mastering_display: Option<Vec<u8>>,
+    /// `AVContentLightMetadata` of the brightest pixel and frame.
+    pub(crate) content_light: Option<Vec<u8>>,
+}
+
+impl HdrMetadata {
+    /// Returns every piece of the metadata with its types of packet and frame side data.
+    pub(crate) fn side_data(
+        &self,
+    ) -> impl Iterator<
+        Item = (
+            ffmpeg::codec::packet::side_data::Type,
+            ffmpeg::frame::side_data::Type,
+            &[u8],
+        ),
+    > {
+        [
+            (
+                ffmpeg::codec::packet::side_data::Type::MasteringDisplayMetadata,
+                ffmpeg::frame::side_data::Type::MasteringDisplayMetadata,
+                &self.mastering_display,
+            ),
+            (
+                ffmpeg::codec::packet::side_data::Type::ContentLightLevel,
+                ffmpeg::frame::side_data::Type::ContentLightLevel,
+                &self.content_light,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(packet_kind, frame_kind, data)| {
+            Some((packet_kind, frame_kind, data.as_deref()?))
+        })
+    }
+}
+
+pub(crate) struct Source {
+    pub(crate) input_file: PathBuf,
+    pub(crate) input_ctx: ffmpeg::format::context::Input,
+    pub(crate) decoders: HashMap<StreamId, VideoDecoder>,
+    /// Rotations of video streams stored in their display matrices.
+    orientations: HashMap<StreamId, Rotate>,
+    pub(crate) properties: HashMap<StreamId, VideoProperties>,
+    pub(crate) time_bases: HashMap<StreamId, ffmpeg::Rational>,
+    /// Decoded video streams found interlaced.
+    interlaced: HashMap<StreamId, bool>,
+}
+
+impl Source {
+    /// Opens the input file and makes decoders for video streams the method changes. Every
+    /// stream is changed when the transform goes beyond what the display matrix can describe,
+    /// e.g. tilts. Decoded streams are checked for interlacing as the transform asks.
+    pub(crate) fn load<Input: AsRef<Path>>(
+        input: Input,
+        method: &Method,
+        transform: &Transform,
+    ) -> anyhow::Result<Self> {
+        let input_ctx = ffmpeg::format::input(input.as_ref())?;
+
+        ffmpeg::format::context::input::dump(&input_ctx, 0, input.as_ref().to_str());
+
+        let mut decoders = HashMap::new();
+        let mut orientations = HashMap::new();
+        let mut properties = HashMap::new();
+        let mut time_bases = HashMap::new();
+        let mut interlaced = HashMap::new();
+        for (index, stream) in input_ctx.streams().enumerate() {
+            let media_type = stream.parameters().medium();
+            time_bases.insert(index.into(), stream.time_base());
+
+            let orientation = display_orientation(&stream.parameters()).unwrap_or(Rotate::Deg0);
+            orientations.insert(index.into(), orientation);
+
+            // Video streams are only decoded when they have to be re-encoded, otherwise they
+            // are stream copied just like the rest. Attached pictures aren't videos at all, and
+            // dropped streams aren't written anywhere.
+            if media_type == ffmpeg::media::Type::Video
+                && !is_attached_picture(&stream)
+                && transform.applies_to(index)
+                && transform.output_index(index).is_some()
+                && method.needs_decoding(&orientation, transform.needs_reencoding())
+            {
+                let decoder_context = ffmpeg::codec::Context::from_parameters(stream.parameters())?;
+                let mut decoder = decoder_context.decoder().video()?;
+                decoder.set_parameters(stream.parameters())?;
+
+                properties.insert(
+                    index.into(),
+                    VideoProperties {
+                        codec: stream.parameters().id(),
+                        width: decoder.width(),
+                        height: decoder.height(),
+                        frame_rate: frame_rate(&stream),
+                        bit_rate: (decoder.bit_rate() > 0).then(|| decoder.bit_rate()),
+                    },
+                );
+                decoders.insert(index.into(), decoder.into());
+
+                let field_order = unsafe { (*stream.parameters().as_ptr()).field_order };
+                let is_interlaced = match ffmpeg::FieldOrder::from(field_order) {
+                    ffmpeg::FieldOrder::Progressive | ffmpeg::FieldOrder::Unknown => {
+                        transform.deinterlace == Deinterlace::Detect
+                            && detect_interlacing(input.as_ref(), index)?
+                    }
+                    _ => true,
+                };
+                interlaced.insert(index.into(), is_interlaced);
+            }
+        }
+
+        for &index in transform.video_streams.iter().flatten() {
+            match input_ctx.stream(index) {
+                Some(stream)
+                    if stream.parameters().medium() == ffmpeg::media::Type::Video
+                        && !is_attached_picture(&stream) => {}
+                _ => anyhow::bail!("The stream #{index} isn't a video"),
+            }
+        }
+
+        for &index in transform.tracks.keys() {
+            if input_ctx.stream(index).is_none() {
+                anyhow::bail!("The input has no stream #{index}");
+            }
+        }
+
+        // Matroska stores bitrates only for the whole file. It's all the video's if that's the
+        // only stream without its own bitrate.
+        let unknown: Vec<StreamId> = properties
+            .iter()
+            .filter(|(_, properties)| properties.bit_rate.is_none())
+            .map(|(id, _)| *id)
+            .collect();
+        if let [id] = unknown[..] {
+            let known_bit_rate: i64 = input_ctx
+                .streams()
+                .filter(|stream| stream.index() != id.0)
+                .map(|stream| unsafe { (*stream.parameters().as_ptr()).bit_rate })
+                .sum();
+            let bit_rate = input_ctx.bit_rate() - known_bit_rate;
+
+            if bit_rate > 0 {
+                properties.get_mut(&id).unwrap().bit_rate = Some(bit_rate as usize);
+            }
+        }
+
+        Ok(Self {
+            input_file: input.as_ref().to_owned(),
+            input_ctx,
+            decoders,
+            orientations,
+            properties,
+            time_bases,
+            interlaced,
+        })
+    }
+
+    /// Returns the seconds of the first timestamp of the file, which times within the video are
+    /// measured from.
+    pub(crate) fn start_time(&self) -> f64 {
+        match unsafe { (*self.input_ctx.as_ptr()).start_time } {
+            ffmpeg::ffi::AV_NOPTS_VALUE => 0.0,
+            start_time => start_time as f64 * f64::from(ffmpeg::rescale::TIME_BASE),
+        }
+    }
+
+    /// Returns the width of pixels of the stream relative to their height, as the container or
+    /// else the codec tells it. Pixels are square when neither does.
+    pub(crate) fn sample_aspect_ratio(&self, stream_id: &StreamId) -> ffmpeg::Rational {
+        let Some(stream) = self.input_ctx.stream(stream_id.0) else {
+            return ffmpeg::Rational::new(1, 1);
+        };
+
+        let sample_aspect_ratio: ffmpeg::Rational = unsafe {
+            ffmpeg::ffi::av_guess_sample_aspect_ratio(
+                self.input_ctx.as_ptr() as *mut _,
+                stream.as_ptr() as *mut _,
+                std::ptr::null_mut(),
+            )
+        }
+        .into();
+
+        if sample_aspect_ratio.numerator() > 0 && sample_aspect_ratio.denominator() > 0 {
+            sample_aspect_ratio.reduce()
+        } else {
+            ffmpeg::Rational::new(1, 1)
+        }
+    }
+
+    /// Returns the HDR metadata the container stores for the stream.
+    pub(crate) fn hdr_metadata(&self, stream_id: &StreamId) -> HdrMetadata {
+        let Some(stream) = self.input_ctx.stream(stream_id.0) else {
+            return HdrMetadata::default();
+        };
+
+        let find = |kind| {
+            stream
+                .side_data()
+                .find(|side_data| side_data.kind() == kind)
+                .map(|side_data| side_data.data().to_vec())
+        };
+        HdrMetadata {
+            mastering_display: find(
+                ffmpeg::codec::packet::side_data::Type::MasteringDisplayMetadata,
+            ),
+            content_light: find(ffmpeg::codec::packet::side_data::Type::ContentLightLevel),
+        }
+    }
+
+    /// Whether the decoded stream is interlaced.
+    pub(crate) fn is_interlaced(&self, stream_id: &StreamId) -> bool {
+        self.interlaced.get(stream_id).copied().unwrap_or(false)
+    }
+
+    /// Returns the rotation players apply to the stream when showing it.
+    pub(crate) fn orientation(&self, stream_id: &StreamId) -> Rotate {
+        self.orientations
+            .get(stream_id)
+            .copied()
+            .unwrap_or(Rotate::Deg0)
+    }
+}
+
+/// Decodes the first frames of the video stream and tells whether the `idet` filter finds most
+/// of them interlaced. The file is opened anew, so the source keeps reading from the start.
+fn detect_interlacing(input: &Path, stream_index: usize) -> anyhow::Result<bool> {
+    let mut input_ctx = ffmpeg::format::input(input)?;
+    let mut decoder = {
+        let stream = input_ctx
+            .stream(stream_index)
+            .ok_or(anyhow::anyhow!("Found missing stream in source."))?;
+        let decoder_context = ffmpeg::codec::Context::from_parameters(stream.parameters())?;
+        decoder_context.decoder().video()?
+    };
+
+    // The graph is made for the first frame, since the format isn't always known before it.
+    let mut filter_graph = None;
+    let mut frame = ffmpeg::frame::Video::empty();
+    let mut analyzed = ffmpeg::frame::Video::empty();
+    let (mut frames, mut interlaced, mut progressive) = (0, 0, 0);
+
+    for (stream, packet) in input_ctx.packets() {
+        if stream.index() != stream_index {
+            continue;
+        }
+        if frames >= IDET_FRAMES {
+            break;
+        }
+
+        decoder.send_packet(&packet)?;
+        while decoder.receive_frame(&mut frame).is_ok() {
+            let filter_graph = match &mut filter_graph {
+                Some(filter_graph) => filter_graph,
+                None => filter_graph.insert(idet_graph(&frame)?),
+            };
+            filter_graph
+                .get("in")
+                .ok_or(anyhow::anyhow!("Found missing input of filter graph!"))?
+                .source()
+                .add(&frame)?;
+            frames += 1;
+
+            // The counts of frames detected with the context of the others so far.
+            while filter_graph
+                .get("out")
+                .ok_or(anyhow::anyhow!("Found missing output for a filter!"))?
+                .sink()
+                .frame(&mut analyzed)
+                .is_ok()
+            {
+                let metadata = analyzed.metadata();
+                let count = |key: &str| {
+                    metadata
+                        .get(key)
+                        .and_then(|count| count.parse::<u64>().ok())
+                        .unwrap_or(0)
+                };
+                interlaced = count("lavfi.idet.multiple.tff") + count("lavfi.idet.multiple.bff");
+                progressive = count("lavfi.idet.multiple.progressive");
+            }
+        }
+    }
+
+    Ok(interlaced > progressive)
+}
+
+/// Makes the graph passing frames like the given one through `idet`.
+fn idet_graph(frame: &ffmpeg::frame::Video) -> anyhow::Result<ffmpeg::filter::Graph> {
+    let mut filter_graph = ffmpeg::filter::Graph::new();
+
+    let pix_fmt = frame
+        .format()
+        .descriptor()
+        .ok_or(anyhow::anyhow!("Unknown pixel format of the video"))?
+        .name();
+    let filter_args = format!(
+        "video_size={}x{}:pix_fmt={pix_fmt}:time_base=1/1:pixel_aspect=1/1",
+        frame.width(),
+        frame.height()
+    );
+
+    filter_graph.add(&ffmpeg::filter::find("buffer").unwrap(), "in", &filter_args)?;
+    filter_graph.add(&ffmpeg::filter::find("buffersink").unwrap(), "out", "")?;
+    filter_graph
+        .output("in", 0)?
+        .input("out", 0)?
+        .parse("idet")?;
+    filter_graph.validate()?;
+
+    Ok(filter_graph)
+}
+
+/// Frames analyzed by [`detect_interlacing`], which is enough for a few seconds of the video.
+const IDET_FRAMES: usize = 120;
+
+/// Returns the frame rate of the stream. Phones record with a variable one, whose average is
+/// the most telling, while some files claim none or bogus rates like 90000 fps.
+fn frame_rate(stream: &ffmpeg::Stream) -> Option<ffmpeg::Rational> {
+    [stream.avg_frame_rate(), stream.rate()]
+        .into_iter()
+        .find(|frame_rate| {
+            frame_rate.numerator() > 0
+                && frame_rate.denominator() > 0
+                && f64::from(*frame_rate) <= MAX_FRAME_RATE
+        })
+}
+
+/// Frames per second of high speed cameras, beyond which rates are considered bogus.
+const MAX_FRAME_RATE: f64 = 1000.0;
+
+/// Returns the processed part of the input file when the packet is read, if it has a timestamp
+/// and the duration of the stream or the file is known.
+pub(crate) fn fraction(
+    stream: &ffmpeg::Stream,
+    packet: &ffmpeg::Packet,
+    total_duration: i64,
+) -> Option<f64> {
+    let current_time = packet.pts()?;
+
+    // Not every container stores the duration of streams.
+    let fraction = if stream.duration() > 0 {
+        current_time as f64 / stream.duration() as f64
+    } else if total_duration > 0 {
+        current_time as f64 * f64::from(stream.time_base())
+            / (total_duration as f64 * f64::from(ffmpeg::rescale::TIME_BASE))
+    } else {
+        return None;
+    };
+
+    fraction.is_finite().then(|| fraction.clamp(0.0, 1.0))
+}
@@ -0,0 +1,121 @@
+use ffmpeg_next as ffmpeg;
+
+use std::path::Path;
+
+use crate::{cover::is_attached_picture, progress::StreamKind};
+
+/// A stream of the input file, listed by [`tracks`] to choose what the output keeps.
+#[derive(Clone, Debug)]
+pub struct Track {
+    /// The index of the stream in the file, which [`crate::PipelineBuilder::track`] takes.
+    pub index: usize,
+    /// The media type of the stream.
+    pub kind: StreamKind,
+    /// The name of the codec, e.g. `aac`.
+    pub codec: String,
+    /// The language tag, e.g. `eng`.
+    pub language: Option<String>,
+    /// The title of the stream, e.g. `Director's commentary`.
+    pub title: Option<String>,
+    /// Players pick the stream by default among the streams of the same kind.
+    pub default: bool,
+    /// Subtitles shown even when subtitles are off, e.g. for foreign dialogue.
+    pub forced: bool,
+    /// The stream is a picture attached to the file, e.g. the cover art, rather than a video.
+    pub attached_picture: bool,
+}
+
+/// What happens to a stream of the input file in the output, see
+/// [`crate::PipelineBuilder::track`]. Streams without options are kept as they are.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TrackOptions {
+    /// The stream is written into the output. Defaults to `true`.
+    pub keep: bool,
+    /// Sets or clears the default disposition, the player picks such tracks first.
+    pub default: Option<bool>,
+    /// Sets or clears the forced disposition, mostly of subtitles shown regardless of the
+    /// language chosen by the viewer.
+    pub forced: Option<bool>,
+    /// Replaces the language tag, e.g. `eng`. An empty tag removes it.
+    pub language: Option<String>,
+}
+
+impl Default for TrackOptions {
+    fn default() -> Self {
+        Self {
+            keep: true,
+            default: None,
+            forced: None,
+            language: None,
+        }
+    }
+}
+
+impl TrackOptions {
+    /// Options dropping the stream from the output.
+    pub fn dropped() -> Self {
+        Self {
+            keep: false,
+            ..Self::default()
+        }
+    }
+}
+
+/// Lists streams of the input file.
+pub fn tracks<P: AsRef<Path>>(input: P) -> anyhow::Result<Vec<Track>> {
+    let input_ctx = ffmpeg::format::input(input.as_ref())?;
+
+    Ok(input_ctx
+        .streams()
+        .map(|stream| {
+            let parameters = stream.parameters();
+            let metadata = stream.metadata();
+            let disposition = stream.disposition();
+            Track {
+                index: stream.index(),
+                kind: parameters.medium().into(),
+                codec: parameters.id().name().to_owned(),
+                language: metadata.get("language").map(str::to_owned),
+                title: metadata.get("title").map(str::to_owned),
+                default: disposition.contains(ffmpeg::format::stream::Disposition::DEFAULT),
+                forced: disposition.contains(ffmpeg::format::stream::Disposition::FORCED),
+                attached_picture: is_attached_picture(&stream),
+            }
+        })
+        .collect())
+}
+
+/// Copies dispositions and the language and title tags of the input stream, so e.g. cover art
+/// stays attached and the default tracks stay default, then applies the options on top.
+pub(crate) fn copy_track(
+    output_stream: &mut ffmpeg::StreamMut,
+    input_stream: &ffmpeg::Stream,
+    options: Option<&TrackOptions>,
+) {
+    let input_metadata = input_stream.metadata();
+    let language = match options.and_then(|options| options.language.as_deref()) {
+        Some(language) => Some(language.trim()).filter(|language| !language.is_empty()),
+        None => input_metadata.get("language"),
+    };
+
+    let mut metadata = ffmpeg::Dictionary::new();
+    if let Some(language) = language {
+        metadata.set("language", language);
+    }
+    if let Some(title) = input_metadata.get("title") {
+        metadata.set("title", title);
+    }
+
+    let mut disposition = input_stream.disposition();
+    if let Some(default) = options.and_then(|options| options.default) {
+        disposition.set(ffmpeg::format::stream::Disposition::DEFAULT, default);
+    }
+    if let Some(forced) = options.and_then(|options| options.forced) {
+        disposition.set(ffmpeg::format::stream::Disposition::FORCED, forced);
+    }
+
+    output_stream.set_metadata(metadata);
+    unsafe {
+        (*output_stream.as_mut_ptr()).disposition = disposition.bits();
+    }
+}
@@ -0,0 +1,1105 @@
+use ffmpeg_next as ffmpeg;
+
+use std::{collections::HashMap, str::FromStr, time::Duration};
+
+use crate::{
+    rotate::{Fill, Rotate, Tilt, parse_color, round_to_even},
+    track::TrackOptions,
+};
+
+/// A step changing frames of re-encoded video streams. Operations are applied in the order they
+/// are added, after [`crate::PipelineBuilder::rotate`] and [`crate::PipelineBuilder::tilt`].
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Operation {
+    /// Rotates or mirrors the frame.
+    Rotate(Rotate),
+    /// Rotates the frame by an arbitrary angle.
+    Tilt(Tilt),
+    /// Cuts the rectangle out of the frame. It's centered unless the offset of its top left
+    /// corner is given.
+    Crop {
+        /// The width of the rectangle.
+        width: u32,
+        /// The height of the rectangle.
+        height: u32,
+        /// The position of the top left corner in the frame.
+        offset: Option<(u32, u32)>,
+    },
+    /// Resizes the frame.
+    Scale(Scale),
+    /// Places the frame onto a larger one filled with the RGB color. It's centered unless the
+    /// offset of its top left corner is given.
+    Pad {
+        /// The width of the larger frame.
+        width: u32,
+        /// The height of the larger frame.
+        height: u32,
+        /// The position of the top left corner of the frame on the larger one.
+        offset: Option<(u32, u32)>,
+        /// The RGB color of the uncovered area.
+        color: [u8; 3],
+    },
+    /// Brings the frame to the aspect ratio `width:height`, e.g. 9:16, by cropping it or by
+    /// growing it with the fill. The cropped rectangle or the frame on the larger one is centered
+    /// unless the offset of its top left corner is given.
+    Aspect {
+        /// The width part of the ratio.
+        width: u32,
+        /// The height part of the ratio.
+        height: u32,
+        /// Whether the frame is cropped or grown.
+        fill: Fill,
+        /// The position of the top left corner of the cropped rectangle in the frame, or of the
+        /// frame on the larger one.
+        offset: Option<(u32, u32)>,
+    },
+    /// Keeps only the time range of the video, the end of the video if `end` is `None`. Stream
+    /// copied tracks are cut the same way, so they stay in sync.
+    Trim {
+        /// The time the output begins at.
+        start: Duration,
+        /// The time the output ends at.
+        end: Option<Duration>,
+    },
+    /// Converts the video to the constant frame rate, duplicating or dropping frames.
+    Fps(f64),
+}
+
+/// The size [`Operation::Scale`] brings frames to. Sizes are rounded down to even numbers.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Scale {
+    /// Exactly the size, stretching the frame if the aspect ratio differs.
+    Fixed {
+        /// The width of the scaled frame.
+        width: u32,
+        /// The height of the scaled frame.
+        height: u32,
+    },
+    /// Fits the frame into the size keeping the aspect ratio, without limiting the missing side.
+    /// Smaller frames aren't enlarged.
+    Max {
+        /// The largest width of the scaled frame.
+        width: Option<u32>,
+        /// The largest height of the scaled frame.
+        height: Option<u32>,
+    },
+    /// Fits the shorter side into the number of lines keeping the aspect ratio, e.g. 1080 for
+    /// 1080p in both landscape and portrait. Smaller frames aren't enlarged.
+    Lines(u32),
+    /// Percent of the size keeping the aspect ratio.
+    Percent(f64),
+}
+
+impl Scale {
+    /// Returns the size of the scaled frame of the given size.
+    fn output_size(&self, width: u32, height: u32) -> anyhow::Result<(u32, u32)> {
+        let factor = match *self {
+            Scale::Fixed { width, height } => {
+                check_size("scale", width, height)?;
+                return Ok((width, height));
+            }
+            Scale::Max {
+                width: max_width,
+                height: max_height,
+            } => {
+                if max_width == Some(0) || max_height == Some(0) {
+                    anyhow::bail!("The frame can't be fitted into a zero size");
+                }
+                let width_factor = max_width.map_or(1.0, |max| f64::from(max) / f64::from(width));
+                let height_factor =
+                    max_height.map_or(1.0, |max| f64::from(max) / f64::from(height));
+                width_factor.min(height_factor).min(1.0)
+            }
+            Scale::Lines(0) => anyhow::bail!("The frame can't be scaled to 0 lines"),
+            Scale::Lines(lines) => (f64::from(lines) / f64::from(width.min(height))).min(1.0),
+            Scale::Percent(percent) => {
+                if !percent.is_finite() || percent <= 0.0 {
+                    anyhow::bail!("Invalid scale {percent}%");
+                }
+                percent / 100.0
+            }
+        };
+
+        Ok((
+            round_to_even(f64::from(width) * factor),
+            round_to_even(f64::from(height) * factor),
+        ))
+    }
+}
+
+/// Parses `WxH` for [`Scale::Fixed`], `fit:WxH` for [`Scale::Max`] with either side possibly
+/// empty, e.g. `fit:1920x` or `fit:x1080`, `1080p` for [`Scale::Lines`] and `50%` for
+/// [`Scale::Percent`].
+impl FromStr for Scale {
+    type Err = anyhow::Error;
+
+    fn from_str(scale: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow::anyhow!("Invalid scale {scale}");
+
+        if let Some(percent) = scale.strip_suffix('%') {
+            return Ok(Scale::Percent(percent.parse().map_err(|_| invalid())?));
+        }
+        if let Some(lines) = scale.strip_suffix('p') {
+            return match lines.parse() {
+                Ok(0) | Err(_) => Err(invalid()),
+                Ok(lines) => Ok(Scale::Lines(lines)),
+            };
+        }
+        if let Some(size) = scale.strip_prefix("fit:") {
+            let (width, height) = size.split_once('x').ok_or_else(invalid)?;
+            let parse_side = |side: &str| match side {
+                "" => Ok(None),
+                side => match side.parse() {
+                    Ok(0) | Err(_) => Err(invalid()),
+                    Ok(side) => Ok(Some(side)),
+                },
+            };
+            return Ok(Scale::Max {
+                width: parse_side(width)?,
+                height: parse_side(height)?,
+            });
+        }
+
+        let (width, height) = parse_size(scale)?;
+        Ok(Scale::Fixed { width, height })
+    }
+}
+
+/// The size and timing of frames between operations.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Geometry {
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    /// The width of a pixel relative to its height, which isn't 1 in anamorphic videos.
+    pub(crate) sample_aspect_ratio: ffmpeg::Rational,
+    pub(crate) frame_rate: Option<ffmpeg::Rational>,
+    /// Seconds of the first timestamp of the input file, which times within the video are
+    /// measured from.
+    pub(crate) start_time: f64,
+}
+
+/// Returns the filters stretching non-square pixels into square ones, followed by a comma, and
+/// changes the geometry to match. The frames are only grown, so no detail is lost.
+pub(crate) fn square_pixels(geometry: &mut Geometry) -> String {
+    let sample_aspect_ratio = f64::from(geometry.sample_aspect_ratio);
+    if sample_aspect_ratio == 1.0 {
+        return String::new();
+    }
+
+    if sample_aspect_ratio > 1.0 {
+        geometry.width = round_to_even(f64::from(geometry.width) * sample_aspect_ratio);
+    } else {
+        geometry.height = round_to_even(f64::from(geometry.height) / sample_aspect_ratio);
+    }
+    geometry.sample_aspect_ratio = ffmpeg::Rational::new(1, 1);
+
+    format!("scale={}:{},setsar=1,", geometry.width, geometry.height)
+}
+
+impl Operation {
+    /// Returns the filters of the operation for frames of the given geometry and pixel format,
+    /// and changes the geometry into the one of the result. Fails if the operation doesn't fit
+    /// the frames.
+    pub(crate) fn as_filter(
+        &self,
+        geometry: &mut Geometry,
+        pixel_format: &str,
+    ) -> anyhow::Result<String> {
+        let (width, height) = (geometry.width, geometry.height);
+
+        match *self {
+            // The transpose filter turns pixels too, so their aspect ratio is inverted.
+            Operation::Rotate(rotate) => {
+                if rotate.is_axis_flips() {
+                    (geometry.width, geometry.height) = (height, width);
+                    geometry.sample_aspect_ratio = geometry.sample_aspect_ratio.invert();
+                }
+                Ok(rotate.as_filter().to_owned())
+            }
+            // Non-square pixels would be skewed by turning them.
+            Operation::Tilt(tilt) => {
+                let square = square_pixels(geometry);
+                let (width, height) = (geometry.width, geometry.height);
+
+                (geometry.width, geometry.height) = tilt.output_size(width, height);
+                Ok(square + &tilt.as_filter(width, height, pixel_format))
+            }
+            Operation::Crop {
+                width: crop_width,
+                height: crop_height,
+                offset,
+            } => {
+                check_size("crop", crop_width, crop_height)?;
+                let (x, y) = offset.unwrap_or((
+                    width.saturating_sub(crop_width) / 2,
+                    height.saturating_sub(crop_height) / 2,
+                ));
+                if !fits(x, crop_width, width) || !fits(y, crop_height, height) {
+                    anyhow::bail!(
+                        "The crop {crop_width}x{crop_height} at {x},{y} doesn't fit the \
+                         {width}x{height} video"
+                    );
+                }
+
+                (geometry.width, geometry.height) = (crop_width, crop_height);
+                Ok(format!("crop={crop_width}:{crop_height}:{x}:{y}"))
+            }
+            // A fixed size is what the video is shown in, while the rest keep the shape of pixels.
+            Operation::Scale(scale) => {
+                let (scale_width, scale_height) = scale.output_size(width, height)?;
+                if let Scale::Fixed { .. } = scale {
+                    geometry.sample_aspect_ratio = ffmpeg::Rational::new(1, 1);
+                }
+
+                (geometry.width, geometry.height) = (scale_width, scale_height);
+                Ok(format!(
+                    "scale={scale_width}:{scale_height},setsar={}/{}",
+                    geometry.sample_aspect_ratio.numerator(),
+                    geometry.sample_aspect_ratio.denominator()
+                ))
+            }
+            Operation::Pad {
+                width: pad_width,
+                height: pad_height,
+                offset,
+                color: [red, green, blue],
+            } => {
+                check_size("pad", pad_width, pad_height)?;
+                let (x, y) = offset.unwrap_or((
+                    pad_width.saturating_sub(width) / 2,
+                    pad_height.saturating_sub(height) / 2,
+                ));
+                if !fits(x, width, pad_width) || !fits(y, height, pad_height) {
+                    anyhow::bail!(
+                        "The {width}x{height} video at {x},{y} doesn't fit the pad \
+                         {pad_width}x{pad_height}"
+                    );
+                }
+
+                (geometry.width, geometry.height) = (pad_width, pad_height);
+                Ok(format!(
+                    "pad={pad_width}:{pad_height}:{x}:{y}:color=0x{red:02x}{green:02x}{blue:02x}"
+                ))
+            }
+            Operation::Aspect {
+                width: ratio_width,
+                height: ratio_height,
+                fill,
+                offset,
+            } => {
+                if ratio_width == 0 || ratio_height == 0 {
+                    anyhow::bail!("Invalid aspect ratio {ratio_width}:{ratio_height}");
+                }
+                // The ratio is the one the video is shown in, so pixels are made square first.
+                let square = square_pixels(geometry);
+                let (width, height) = (geometry.width, geometry.height);
+                let ratio = f64::from(ratio_width) / f64::from(ratio_height);
+                let wider = f64::from(width) / f64::from(height) > ratio;
+
+                if fill == Fill::Crop {
+                    let (crop_width, crop_height) = if wider {
+                        (f64::from(height) * ratio, f64::from(height))
+                    } else {
+                        (f64::from(width), f64::from(width) / ratio)
+                    };
+                    let crop = Operation::Crop {
+                        width: round_to_even(crop_width),
+                        height: round_to_even(crop_height),
+                        offset,
+                    };
+                    return Ok(square + &crop.as_filter(geometry, pixel_format)?);
+                }
+
+                // The frame is grown, so the size is rounded up to keep it inside.
+                let (pad_width, pad_height) = if wider {
+                    (f64::from(width), f64::from(width) / ratio)
+                } else {
+                    (f64::from(height) * ratio, f64::from(height))
+                };
+                let (pad_width, pad_height) =
+                    (round_up_to_even(pad_width), round_up_to_even(pad_height));
+
+                match fill {
+                    Fill::Color(color) => Ok(square
+                        + &Operation::Pad {
+                            width: pad_width,
+                            height: pad_height,
+                            offset,
+                            color,
+                        }
+                        .as_filter(geometry, pixel_format)?),
+                    // The frame is laid over the blurred copy of itself stretched to the whole
+                    // size.
+                    _ => {
+                        let (x, y) =
+                            offset.unwrap_or(((pad_width - width) / 2, (pad_height - height) / 2));
+                        if !fits(x, width, pad_width) || !fits(y, height, pad_height) {
+                            anyhow::bail!(
+                                "The {width}x{height} video at {x},{y} doesn't fit the \
+                                 {pad_width}x{pad_height} frame"
+                            );
+                        }
+
+                        (geometry.width, geometry.height) = (pad_width, pad_height);
+                        Ok(format!(
+                            "{square}split[aspect_bg][aspect_fg];\
+                             [aspect_bg]scale={pad_width}:{pad_height}:\
+                             force_original_aspect_ratio=increase,crop={pad_width}:{pad_height},\
+                             setsar=1,gblur=sigma=30[aspect_blurred];\
+                             [aspect_blurred][aspect_fg]overlay={x}:{y},format={pixel_format}"
+                        ))
+                    }
+                }
+            }
+            // Timestamps are moved back by the start, which stream copied tracks are moved by too.
+            Operation::Trim { start, end } => {
+                let start = geometry.start_time + start.as_secs_f64();
+                let mut trim = format!("trim=start={start:.6}");
+                if let Some(end) = end {
+                    trim += &format!(":end={:.6}", geometry.start_time + end.as_secs_f64());
+                }
+
+                Ok(format!("{trim},setpts=PTS-{start:.6}/TB"))
+            }
+            Operation::Fps(fps) => {
+                if !fps.is_finite() || fps <= 0.0 {
+                    anyhow::bail!("Invalid frame rate {fps}");
+                }
+                let frame_rate = ffmpeg::Rational::from(fps);
+
+                geometry.frame_rate = Some(frame_rate);
+                Ok(format!(
+                    "fps=fps={}/{}",
+                    frame_rate.numerator(),
+                    frame_rate.denominator()
+                ))
+            }
+        }
+    }
+}
+
+/// Parses `NAME=VALUE`, where it's one of:
+/// - `rotate=ROTATION` with the rotation of [`Rotate`], e.g. `rotate=90` or `rotate=hflip`;
+/// - `tilt=DEGREES[:FILL]`, e.g. `tilt=-3.5:blur`;
+/// - `crop=WxH[+X+Y]`, e.g. `crop=1080x1080` or `crop=1080x1080+0+420`;
+/// - `scale=SCALE` with the scale of [`Scale`], e.g. `scale=1280x720`, `scale=fit:1920x1080`,
+///   `scale=1080p` or `scale=50%`;
+/// - `pad=WxH[+X+Y][:COLOR]`, e.g. `pad=1920x1080:#202020`;
+/// - `aspect=W:H[:FILL][+X+Y]`, e.g. `aspect=9:16`, `aspect=4:5:blur` or `aspect=9:16+200+0`;
+/// - `trim=START-END` with times of [`Segment`], e.g. `trim=0:05-1:30` or `trim=10-`;
+/// - `fps=FPS`, e.g. `fps=30` or `fps=30000/1001`.
+impl FromStr for Operation {
+    type Err = anyhow::Error;
+
+    fn from_str(operation: &str) -> Result<Self, Self::Err> {
+        let (name, value) = operation
+            .split_once('=')
+            .ok_or(anyhow::anyhow!("Expected NAME=VALUE, got {operation}"))?;
+        let (value, option) = match value.split_once(':') {
+            // Times of `trim`, ratios of `aspect` and `fit:` of `scale` have colons themselves.
+            Some((value, option)) if !matches!(name, "trim" | "aspect" | "scale") => {
+                (value, Some(option))
+            }
+            _ => (value, None),
+        };
+
+        match (name, option) {
+            ("rotate", None) => Ok(Operation::Rotate(value.parse()?)),
+            ("tilt", fill) => Ok(Operation::Tilt(Tilt {
+                degrees: value
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid tilt {value}"))?,
+                fill: fill.map_or(Ok(Fill::Crop), str::parse)?,
+            })),
+            ("crop", None) => {
+                let (width, height, offset) = parse_rectangle(value)?;
+                Ok(Operation::Crop {
+                    width,
+                    height,
+                    offset,
+                })
+            }
+            ("scale", None) => Ok(Operation::Scale(value.parse()?)),
+            ("pad", color) => {
+                let (width, height, offset) = parse_rectangle(value)?;
+                Ok(Operation::Pad {
+                    width,
+                    height,
+                    offset,
+                    color: color.map_or(Ok([0, 0, 0]), parse_color)?,
+                })
+            }
+            ("aspect", None) => {
+                let (value, offset) = match value.split_once('+') {
+                    Some((value, offset)) => (value, Some(parse_offset(offset)?)),
+                    None => (value, None),
+                };
+                let mut parts = value.splitn(3, ':');
+                let (Some(width), Some(height)) = (parts.next(), parts.next()) else {
+                    anyhow::bail!("Expected aspect=W:H[:FILL][+X+Y], got {operation}");
+                };
+
+                Ok(Operation::Aspect {
+                    width: width
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("Invalid aspect ratio {value}"))?,
+                    height: height
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("Invalid aspect ratio {value}"))?,
+                    fill: parts.next().map_or(Ok(Fill::Crop), str::parse)?,
+                    offset,
+                })
+            }
+            ("trim", None) => {
+                let (start, end) = parse_range(value)?;
+                Ok(Operation::Trim { start, end })
+            }
+            ("fps", None) => {
+                let invalid = || anyhow::anyhow!("Invalid frame rate {value}");
+                let fps = match value.split_once('/') {
+                    Some((numerator, denominator)) => {
+                        numerator.parse::<f64>().map_err(|_| invalid())?
+                            / denominator.parse::<f64>().map_err(|_| invalid())?
+                    }
+                    None => value.parse().map_err(|_| invalid())?,
+                };
+                Ok(Operation::Fps(fps))
+            }
+            _ => anyhow::bail!("Unknown operation {operation}"),
+        }
+    }
+}
+
+/// Parses a frame size `WxH`, e.g. `1920x1080`.
+pub fn parse_size(size: &str) -> anyhow::Result<(u32, u32)> {
+    size.split_once('x')
+        .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+        .ok_or(anyhow::anyhow!(
+            "Invalid size {size}, expected WIDTHxHEIGHT"
+        ))
+}
+
+/// Parses `WxH` with the optional offset `+X+Y`.
+fn parse_rectangle(rectangle: &str) -> anyhow::Result<(u32, u32, Option<(u32, u32)>)> {
+    let Some((size, offset)) = rectangle.split_once('+') else {
+        let (width, height) = parse_size(rectangle)?;
+        return Ok((width, height, None));
+    };
+
+    let (width, height) = parse_size(size)?;
+    Ok((width, height, Some(parse_offset(offset)?)))
+}
+
+/// Parses `X+Y`.
+fn parse_offset(offset: &str) -> anyhow::Result<(u32, u32)> {
+    offset
+        .split_once('+')
+        .and_then(|(x, y)| Some((x.parse().ok()?, y.parse().ok()?)))
+        .ok_or(anyhow::anyhow!("Invalid offset {offset}, expected X+Y"))
+}
+
+fn round_up_to_even(value: f64) -> u32 {
+    ((value / 2.0).ceil() as u32 * 2).max(2)
+}
+
+/// Whether the range of the size starting at the offset ends within the bound. Offsets come from
+/// the user, so the end may not even fit `u32`.
+fn fits(offset: u32, size: u32, bound: u32) -> bool {
+    offset.checked_add(size).is_some_and(|end| end <= bound)
+}
+
+/// Checks that the size of the operation suits encoders, which mostly need even sizes.
+fn check_size(operation: &str, width: u32, height: u32) -> anyhow::Result<()> {
+    if width < 2 || height < 2 || width % 2 != 0 || height % 2 != 0 {
+        anyhow::bail!("The {operation} size {width}x{height} must be even and non-zero");
+    }
+
+    Ok(())
+}
+
+/// A time range of the video rotated differently from the rest, e.g. where the phone was turned
+/// while filming.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Segment {
+    /// The start of the range from the beginning of the video.
+    pub start: Duration,
+    /// The end of the range, the end of the video if `None`.
+    pub end: Option<Duration>,
+    /// The rotation replacing the one of [`crate::PipelineBuilder::rotate`] within the range.
+    pub rotate: Rotate,
+}
+
+impl Segment {
+    fn contains(&self, time: Duration) -> bool {
+        self.start <= time && self.end.is_none_or(|end| time < end)
+    }
+}
+
+/// Parses `START-END=ROTATION`, e.g. `1:05-2:30.5=90` or `2:30-=hflip` for the rest of the video.
+/// Times are seconds, `M:S` or `H:M:S`, the rotation is one of `0`, `90`, `180`, `270`, `hflip`,
+/// `vflip`, `transpose` and `transverse`.
+impl FromStr for Segment {
+    type Err = anyhow::Error;
+
+    fn from_str(segment: &str) -> Result<Self, Self::Err> {
+        let (range, rotation) = segment.split_once('=').ok_or(anyhow::anyhow!(
+            "Expected START-END=ROTATION, got {segment}"
+        ))?;
+        let (start, end) = parse_range(range)?;
+
+        Ok(Segment {
+            start,
+            end,
+            rotate: rotation.trim().parse()?,
+        })
+    }
+}
+
+/// Parses `START-END`, where the end may be empty for the end of the video.
+fn parse_range(range: &str) -> anyhow::Result<(Duration, Option<Duration>)> {
+    let (start, end) = range
+        .split_once('-')
+        .ok_or(anyhow::anyhow!("Expected START-END, got {range}"))?;
+
+    let end = match end.trim() {
+        "" => None,
+        end => Some(parse_time(end)?),
+    };
+    Ok((parse_time(start.trim())?, end))
+}
+
+fn parse_time(time: &str) -> anyhow::Result<Duration> {
+    let invalid = || anyhow::anyhow!("Invalid time {time}");
+
+    let mut seconds = 0.0;
+    for part in time.split(':') {
+        let part: f64 = part.parse().map_err(|_| invalid())?;
+        if !part.is_finite() || part < 0.0 {
+            return Err(invalid());
+        }
+        seconds = seconds * 60.0 + part;
+    }
+
+    Duration::try_from_secs_f64(seconds).map_err(|_| invalid())
+}
+
+/// Whether interlaced video streams are deinterlaced before the rotation, which would mix their
+/// fields into combing otherwise.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum Deinterlace {
+    /// Deinterlaces frames of streams the container or the codec marks interlaced.
+    #[default]
+    Auto,
+    /// Like [`Deinterlace::Auto`], but analyzes the first frames of streams not marked
+    /// interlaced with the `idet` filter, since cameras don't always mark them.
+    Detect,
+    /// Deinterlaces every frame, e.g. when the marks are wrong. It needs re-encoding.
+    Always,
+    /// Keeps the fields as they are.
+    Never,
+}
+
+impl Deinterlace {
+    /// Returns the deinterlacing filter for the stream, if it's needed. `bwdif` is preferred to
+    /// `yadif`, and both keep the frame rate.
+    pub(crate) fn as_filter(&self, interlaced: bool) -> Option<String> {
+        // Only marked frames are deinterlaced, unless the marks can't be trusted.
+        let frames = match self {
+            Deinterlace::Never => return None,
+            Deinterlace::Auto | Deinterlace::Detect if !interlaced => return None,
+            Deinterlace::Auto => "interlaced",
+            Deinterlace::Detect | Deinterlace::Always => "all",
+        };
+        let deinterlacer = if ffmpeg::filter::find("bwdif").is_some() {
+            "bwdif"
+        } else {
+            "yadif"
+        };
+
+        Some(format!(
+            "{deinterlacer}=mode=send_frame:parity=auto:deint={frames}"
+        ))
+    }
+}
+
+/// Parses `auto`, `detect`, `always` or `never`.
+impl FromStr for Deinterlace {
+    type Err = anyhow::Error;
+
+    fn from_str(deinterlace: &str) -> anyhow::Result<Self> {
+        match deinterlace {
+            "auto" => Ok(Deinterlace::Auto),
+            "detect" => Ok(Deinterlace::Detect),
+            "always" => Ok(Deinterlace::Always),
+            "never" => Ok(Deinterlace::Never),
+            _ => anyhow::bail!("Unknown deinterlacing {deinterlace}"),
+        }
+    }
+}
+
+/// Everything done to frames of re-encoded video streams on top of their orientation.
+#[derive(Clone, Default, Debug)]
+pub(crate) struct Transform {
+    pub(crate) rotate: Rotate,
+    pub(crate) tilt: Option<Tilt>,
+    pub(crate) operations: Vec<Operation>,
+    /// The scaling applied after all operations.
+    pub(crate) scale: Option<Scale>,
+    pub(crate) segments: Vec<Segment>,
+    /// The frame size of the output when segments are rotated differently. Frames are scaled to
+    /// fit it and the rest is filled with black.
+    pub(crate) canvas: Option<(u32, u32)>,
+    /// Deinterlacing applied before everything else.
+    pub(crate) deinterlace: Deinterlace,
+    /// Indices of video streams the transform is applied to, all of them if `None`. The rest
+    /// are stream copied as they are.
+    pub(crate) video_streams: Option<Vec<usize>>,
+    /// Whether pictures attached to the file, e.g. cover art, are rotated by
+    /// [`Transform::rotate`] too. They're stream copied otherwise.
+    pub(crate) rotate_attached_pictures: bool,
+    /// Options of streams by their indices in the input file.
+    pub(crate) tracks: HashMap<usize, TrackOptions>,
+}
+
+impl Transform {
+    /// Whether pixels are changed in a way the display matrix can't describe, so every video
+    /// stream has to be re-encoded.
+    pub(crate) fn needs_reencoding(&self) -> bool {
+        self.tilt.is_some()
+            || !self.operations.is_empty()
+            || self.scale.is_some()
+            || !self.segments.is_empty()
+            || self.deinterlace == Deinterlace::Always
+    }
+
+    /// Whether the video stream is chosen to be transformed.
+    pub(crate) fn applies_to(&self, stream_index: usize) -> bool {
+        self.video_streams
+            .as_ref()
+            .is_none_or(|streams| streams.contains(&stream_index))
+    }
+
+    /// Returns the options of the stream, if there are any.
+    pub(crate) fn track(&self, stream_index: usize) -> Option<&TrackOptions> {
+        self.tracks.get(&stream_index)
+    }
+
+    /// Returns the index of the output stream made of the input one, or `None` if it's dropped.
+    pub(crate) fn output_index(&self, stream_index: usize) -> Option<usize> {
+        let keeps = |index| self.track(index).is_none_or(|track| track.keep);
+        keeps(stream_index).then(|| (0..stream_index).filter(|&index| keeps(index)).count())
+    }
+
+    /// Returns the operations making the frame of the stream with the orientation, starting
+    /// with the rotation of the segment if it's given.
+    pub(crate) fn operations(
+        &self,
+        orientation: &Rotate,
+        segment: Option<usize>,
+    ) -> Vec<Operation> {
+        let rotate = match segment {
+            Some(segment) => &self.segments[segment].rotate,
+            None => &self.rotate,
+        };
+
+        let mut operations = vec![Operation::Rotate(orientation.then(rotate))];
+        operations.extend(self.tilt.map(Operation::Tilt));
+        operations.extend(&self.operations);
+        operations.extend(self.scale.map(Operation::Scale));
+        operations
+    }
+
+    /// Returns the time range kept by [`Operation::Trim`].
+    pub(crate) fn trim(&self) -> Option<(Duration, Option<Duration>)> {
+        self.operations
+            .iter()
+            .find_map(|operation| match operation {
+                Operation::Trim { start, end } => Some((*start, *end)),
+                _ => None,
+            })
+    }
+
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        let trims = self
+            .operations
+            .iter()
+            .filter(|operation| matches!(operation, Operation::Trim { .. }))
+            .count();
+        if trims > 1 {
+            anyhow::bail!("The video can be trimmed only once");
+        }
+        if let Some((start, Some(end))) = self.trim()
+            && end <= start
+        {
+            anyhow::bail!("The trimmed range ends before it starts");
+        }
+
+        // Every segment has its own graph, which would fill the time of other segments with
+        // duplicated frames.
+        if !self.segments.is_empty()
+            && self
+                .operations
+                .iter()
+                .any(|operation| matches!(operation, Operation::Fps(_)))
+        {
+            anyhow::bail!("The frame rate can't be changed together with rotating segments");
+        }
+
+        for segment in &self.segments {
+            if segment.end.is_some_and(|end| end <= segment.start) {
+                anyhow::bail!(
+                    "The segment starting at {:.3}s ends before it starts",
+                    segment.start.as_secs_f64()
+                );
+            }
+        }
+
+        if let Some((width, height)) = self
+            .canvas
+            .filter(|(width, height)| *width < 2 || *height < 2)
+        {
+            anyhow::bail!("The canvas {width}x{height} is too small");
+        }
+
+        Ok(())
+    }
+
+    /// Returns the index of the segment containing the time from the beginning of the video. The
+    /// first one wins if segments overlap.
+    pub(crate) fn segment_at(&self, time: Duration) -> Option<usize> {
+        self.segments
+            .iter()
+            .position(|segment| segment.contains(time))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_times() {
+        assert_eq!(parse_time("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_time("1:05").unwrap(), Duration::from_secs(65));
+        assert_eq!(
+            parse_time("1:02:03.5").unwrap(),
+            Duration::from_secs_f64(3723.5)
+        );
+        assert!(parse_time("").is_err());
+        assert!(parse_time("1:").is_err());
+        assert!(parse_time("-5").is_err());
+        assert!(parse_time("inf").is_err());
+        assert!(parse_time("NaN").is_err());
+        // Too long for a duration.
+        assert!(parse_time("1e300").is_err());
+    }
+
+    #[test]
+    fn parse_segments() {
+        assert_eq!(
+            "1:05-2:30.5=90".parse::<Segment>().unwrap(),
+            Segment {
+                start: Duration::from_secs(65),
+                end: Some(Duration::from_secs_f64(150.5)),
+                rotate: Rotate::Deg90,
+            }
+        );
+        assert_eq!(
+            "2:30-=hflip".parse::<Segment>().unwrap(),
+            Segment {
+                start: Duration::from_secs(150),
+                end: None,
+                rotate: Rotate::HFlip,
+            }
+        );
+        assert!("1:05=90".parse::<Segment>().is_err());
+        assert!("1-2".parse::<Segment>().is_err());
+        assert!("1-2=45".parse::<Segment>().is_err());
+        assert!("-2=90".parse::<Segment>().is_err());
+    }
+
+    #[test]
+    fn segment_at() {
+        let transform = Transform {
+            segments: vec!["10-20=90".parse().unwrap(), "15-=180".parse().unwrap()],
+            ..Transform::default()
+        };
+
+        assert_eq!(transform.segment_at(Duration::from_secs(5)), None);
+        assert_eq!(transform.segment_at(Duration::from_secs(10)), Some(0));
+        // The first one wins where they overlap, and ends are exclusive.
+        assert_eq!(transform.segment_at(Duration::from_secs(15)), Some(0));
+        assert_eq!(transform.segment_at(Duration::from_secs(20)), Some(1));
+        assert_eq!(transform.segment_at(Duration::from_secs(3600)), Some(1));
+    }
+
+    fn geometry(width: u32, height: u32) -> Geometry {
+        Geometry {
+            width,
+            height,
+            sample_aspect_ratio: ffmpeg::Rational::new(1, 1),
+            frame_rate: None,
+            start_time: 0.0,
+        }
+    }
+
+    #[test]
+    fn parse_operations() {
+        let parse = |operation: &str| operation.parse::<Operation>().unwrap();
+
+        assert_eq!(
+            parse("rotate=transpose"),
+            Operation::Rotate(Rotate::Transpose)
+        );
+        assert_eq!(
+            parse("tilt=-3.5:blur"),
+            Operation::Tilt(Tilt {
+                degrees: -3.5,
+                fill: Fill::Blur,
+            })
+        );
+        assert_eq!(
+            parse("crop=1080x1080"),
+            Operation::Crop {
+                width: 1080,
+                height: 1080,
+                offset: None,
+            }
+        );
+        assert_eq!(
+            parse("crop=1080x1080+0+420"),
+            Operation::Crop {
+                width: 1080,
+                height: 1080,
+                offset: Some((0, 420)),
+            }
+        );
+        assert_eq!(
+            parse("pad=1920x1080:#202020"),
+            Operation::Pad {
+                width: 1920,
+                height: 1080,
+                offset: None,
+                color: [0x20, 0x20, 0x20],
+            }
+        );
+        assert_eq!(
+            parse("aspect=9:16:blur+200+0"),
+            Operation::Aspect {
+                width: 9,
+                height: 16,
+                fill: Fill::Blur,
+                offset: Some((200, 0)),
+            }
+        );
+        assert_eq!(
+            parse("trim=0:05-1:30"),
+            Operation::Trim {
+                start: Duration::from_secs(5),
+                end: Some(Duration::from_secs(90)),
+            }
+        );
+        assert_eq!(parse("fps=30000/1001"), Operation::Fps(30000.0 / 1001.0));
+
+        for invalid in [
+            "rotate",
+            "rotate=45",
+            "crop=1080",
+            "crop=1080x1080+420",
+            "pad=1920x1080:grey",
+            "aspect=9",
+            "fps=30/x",
+            "blur=5",
+        ] {
+            assert!(invalid.parse::<Operation>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn crop_and_pad_geometry() {
+        let mut geometry = geometry(1920, 1080);
+
+        let crop = Operation::Crop {
+            width: 1080,
+            height: 1080,
+            offset: None,
+        };
+        assert_eq!(
+            crop.as_filter(&mut geometry, "yuv420p").unwrap(),
+            "crop=1080:1080:420:0"
+        );
+        assert_eq!((geometry.width, geometry.height), (1080, 1080));
+
+        let pad = Operation::Pad {
+            width: 1920,
+            height: 1200,
+            offset: Some((0, 60)),
+            color: [255, 0, 0],
+        };
+        assert_eq!(
+            pad.as_filter(&mut geometry, "yuv420p").unwrap(),
+            "pad=1920:1200:0:60:color=0xff0000"
+        );
+        assert_eq!((geometry.width, geometry.height), (1920, 1200));
+    }
+
+    #[test]
+    fn crop_and_pad_out_of_bounds() {
+        let crop = |width, height, offset| Operation::Crop {
+            width,
+            height,
+            offset,
+        };
+        let pad = |width, height, offset| Operation::Pad {
+            width,
+            height,
+            offset,
+            color: [0, 0, 0],
+        };
+
+        for operation in [
+            crop(2000, 1080, None),
+            crop(1080, 1080, Some((900, 0))),
+            // The end of the range overflows `u32`.
+            crop(1080, 1080, Some((u32::MAX - 100, 0))),
+            crop(1081, 1080, None),
+            pad(1280, 720, None),
+            pad(2560, 1440, Some((700, 0))),
+            pad(2560, 1440, Some((0, u32::MAX - 100))),
+        ] {
+            assert!(
+                operation
+                    .as_filter(&mut geometry(1920, 1080), "yuv420p")
+                    .is_err(),
+                "{operation:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_scales() {
+        assert_eq!(
+            "1280x720".parse::<Scale>().unwrap(),
+            Scale::Fixed {
+                width: 1280,
+                height: 720,
+            }
+        );
+        assert_eq!(
+            "fit:1920x".parse::<Scale>().unwrap(),
+            Scale::Max {
+                width: Some(1920),
+                height: None,
+            }
+        );
+        assert_eq!(
+            "fit:x1080".parse::<Scale>().unwrap(),
+            Scale::Max {
+                width: None,
+                height: Some(1080),
+            }
+        );
+        assert_eq!("1080p".parse::<Scale>().unwrap(), Scale::Lines(1080));
+        assert_eq!("50%".parse::<Scale>().unwrap(), Scale::Percent(50.0));
+
+        for invalid in [
+            "1280",
+            "fit:1920",
+            "fit:axb",
+            "p",
+            "half%",
+            "1280x720p",
+            "0p",
+            "fit:0x",
+            "fit:x0",
+            "fit:1920x0",
+        ] {
+            assert!(invalid.parse::<Scale>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn scale_output_size() {
+        let fixed = Scale::Fixed {
+            width: 1280,
+            height: 720,
+        };
+        assert_eq!(fixed.output_size(1080, 1920).unwrap(), (1280, 720));
+        assert!(
+            Scale::Fixed {
+                width: 1281,
+                height: 720,
+            }
+            .output_size(1920, 1080)
+            .is_err()
+        );
+
+        let max = |width, height| Scale::Max { width, height };
+        assert_eq!(
+            max(Some(1280), None).output_size(1920, 1080).unwrap(),
+            (1280, 720)
+        );
+        assert_eq!(
+            max(Some(1920), Some(1920)).output_size(1080, 1920).unwrap(),
+            (1080, 1920)
+        );
+        assert_eq!(
+            max(Some(1280), Some(1280)).output_size(1080, 1920).unwrap(),
+            (720, 1280)
+        );
+        // Smaller frames aren't enlarged.
+        assert_eq!(
+            max(Some(3840), Some(2160)).output_size(1920, 1080).unwrap(),
+            (1920, 1080)
+        );
+
+        assert_eq!(
+            Scale::Lines(720).output_size(1080, 1920).unwrap(),
+            (720, 1280)
+        );
+        assert_eq!(
+            Scale::Lines(2160).output_size(1920, 1080).unwrap(),
+            (1920, 1080)
+        );
+
+        assert_eq!(
+            Scale::Percent(50.0).output_size(1920, 1080).unwrap(),
+            (960, 540)
+        );
+        assert_eq!(
+            Scale::Percent(33.3).output_size(1920, 1080).unwrap(),
+            (638, 358)
+        );
+        assert!(Scale::Percent(0.0).output_size(1920, 1080).is_err());
+        assert!(Scale::Lines(0).output_size(1920, 1080).is_err());
+        assert!(max(Some(0), None).output_size(1920, 1080).is_err());
+        assert!(max(Some(1280), Some(0)).output_size(1920, 1080).is_err());
+        assert!(Scale::Percent(f64::NAN).output_size(1920, 1080).is_err());
+    }
+
+    #[test]
+    fn output_index_skips_dropped_streams() {
+        let transform = Transform {
+            tracks: HashMap::from([
+                (1, TrackOptions::dropped()),
+                (
+                    3,
+                    TrackOptions {
+                        language: Some("eng".to_owned()),
+                        ..TrackOptions::default()
+                    },
+                ),
+                (4, TrackOptions::dropped()),
+            ]),
+            ..Transform::default()
+        };
+
+        assert_eq!(transform.output_index(0), Some(0));
+        assert_eq!(transform.output_index(1), None);
+        assert_eq!(transform.output_index(2), Some(1));
+        assert_eq!(transform.output_index(3), Some(2));
+        assert_eq!(transform.output_index(4), None);
+        assert_eq!(transform.output_index(5), Some(3));
+        assert_eq!(Transform::default().output_index(5), Some(5));
+    }
+}
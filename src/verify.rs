@@ -0,0 +1,218 @@
+use ffmpeg_next as ffmpeg;
+
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hasher},
+    path::Path,
+};
+
+use crate::{
+    StreamId,
+    cancel::{CancelHandle, Cancelled},
+    codec::VideoDecoder,
+    filter::Filter,
+    rotate::Method,
+    source::{self, Source},
+    transform::{Deinterlace, Transform},
+};
+
+/// Checks that the lossless output has exactly the frames of the input passed through the same
+/// transform. The progress receives the verified part from 0.0 to 1.0.
+pub(crate) fn verify<Input: AsRef<Path>, Output: AsRef<Path>, F: FnMut(f64)>(
+    input: Input,
+    output: Output,
+    transform: &Transform,
+    method: &Method,
+    cancel_handle: &CancelHandle,
+    mut progress: F,
+) -> anyhow::Result<()> {
+    let mut source = Source::load(input, method, transform)?;
+    // Frames of output streams are already transformed, but they're compared in the pixel format
+    // of the source, so a format losing some of the data can't pass.
+    let mut output = Source::load(output, &Method::Reencode, &Transform::default())?;
+    let conversion = Transform {
+        deinterlace: Deinterlace::Never,
+        ..Transform::default()
+    };
+
+    let mut filters = HashMap::new();
+    let mut output_filters = HashMap::new();
+    let mut output_streams = HashMap::new();
+    for (id, decoder) in &source.decoders {
+        let output_id: StreamId = transform
+            .output_index(id.0)
+            .ok_or(anyhow::anyhow!("The stream #{} is dropped", id.0))?
+            .into();
+        let output_format = output
+            .decoders
+            .get(&output_id)
+            .ok_or(anyhow::anyhow!(
+                "The output doesn't have the stream #{}",
+                output_id.0
+            ))?
+            .format();
+
+        let pixel_format = decoder.format();
+        filters.insert(*id, Filter::create(&source, id, transform, pixel_format)?);
+        if output_format != pixel_format {
+            output_filters.insert(
+                output_id,
+                Filter::create(&output, &output_id, &conversion, pixel_format)?,
+            );
+        }
+        output_streams.insert(*id, output_id);
+    }
+    let streams: Vec<StreamId> = filters.keys().copied().collect();
+
+    let expected = hash_frames(
+        &mut source,
+        &streams,
+        &mut filters,
+        cancel_handle,
+        |fraction| progress(fraction / 2.0),
+    )?;
+
+    let actual = hash_frames(
+        &mut output,
+        &streams
+            .iter()
+            .map(|id| output_streams[id])
+            .collect::<Vec<_>>(),
+        &mut output_filters,
+        cancel_handle,
+        |fraction| progress(0.5 + fraction / 2.0),
+    )?;
+
+    for id in &streams {
+        let (expected, actual) = (&expected[id], &actual[&output_streams[id]]);
+
+        if let Some(frame) = expected
+            .iter()
+            .zip(actual)
+            .position(|(lhs, rhs)| lhs != rhs)
+        {
+            anyhow::bail!(
+                "The frame {frame} of the stream #{} differs from the source",
+                id.0
+            );
+        }
+
+        if expected.len() != actual.len() {
+            anyhow::bail!(
+                "The stream #{} has {} frames, but the source has {}",
+                id.0,
+                actual.len(),
+                expected.len()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Decodes the streams, passes them through their filters if there are any, and hashes every
+/// frame.
+fn hash_frames<F: FnMut(f64)>(
+    source: &mut Source,
+    streams: &[StreamId],
+    filters: &mut HashMap<StreamId, Filter>,
+    cancel_handle: &CancelHandle,
+    mut progress: F,
+) -> anyhow::Result<HashMap<StreamId, Vec<u64>>> {
+    let mut hashes: HashMap<StreamId, Vec<u64>> = streams.iter().map(|id| (*id, vec![])).collect();
+    let total_duration = source.input_ctx.duration();
+
+    for (stream, packet) in source.input_ctx.packets() {
+        if cancel_handle.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+
+        if let Some(fraction) = source::fraction(&stream, &packet, total_duration) {
+            progress(fraction);
+        }
+
+        let id: StreamId = stream.index().into();
+        let (Some(decoder), Some(hashes)) = (source.decoders.get_mut(&id), hashes.get_mut(&id))
+        else {
+            continue;
+        };
+
+        decoder.send_packet(&packet)?;
+        let filter = filters.get_mut(&id);
+        receive_frames(decoder, filter, hashes)?;
+    }
+
+    for id in streams {
+        let decoder = source
+            .decoders
+            .get_mut(id)
+            .ok_or(anyhow::anyhow!("The stream #{} isn't decoded", id.0))?;
+        let hashes = hashes.get_mut(id).unwrap();
+
+        decoder.send_eof()?;
+        receive_frames(decoder, filters.get_mut(id), hashes)?;
+
+        if let Some(filter) = filters.get_mut(id) {
+            filter.send_eof()?;
+            filter.process_frames(|frame| {
+                hashes.push(hash_frame(frame));
+                Ok(())
+            })?;
+        }
+    }
+
+    Ok(hashes)
+}
+
+fn receive_frames(
+    decoder: &mut VideoDecoder,
+    mut filter: Option<&mut Filter>,
+    hashes: &mut Vec<u64>,
+) -> anyhow::Result<()> {
+    decoder.process_frames(|frame| {
+        let Some(filter) = filter.as_deref_mut() else {
+            hashes.push(hash_frame(frame));
+            return Ok(());
+        };
+
+        let timestamp = frame.timestamp();
+        frame.set_pts(timestamp);
+        filter.send_frame(frame)?;
+        filter.process_frames(|frame| {
+            hashes.push(hash_frame(frame));
+            Ok(())
+        })
+    })
+}
+
+/// Hashes visible pixels of the frame, skipping the padding at the end of lines.
+fn hash_frame(frame: &ffmpeg::frame::Video) -> u64 {
+    let mut hasher = DefaultHasher::new();
+
+    for plane in 0..frame.planes() {
+        let line_size = unsafe {
+            ffmpeg::ffi::av_image_get_linesize(
+                frame.format().into(),
+                frame.width() as i32,
+                plane as i32,
+            )
+        };
+        if line_size <= 0 {
+            continue;
+        }
+
+        // Lines may go upwards, e.g. after `vflip`, so the stride can be negative.
+        let (data, stride) = unsafe {
+            let frame = &*frame.as_ptr();
+            (frame.data[plane], frame.linesize[plane] as isize)
+        };
+        for line in 0..frame.plane_height(plane) as isize {
+            let line = unsafe {
+                std::slice::from_raw_parts(data.offset(line * stride), line_size as usize)
+            };
+            hasher.write(line);
+        }
+    }
+
+    hasher.finish()
+}